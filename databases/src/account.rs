@@ -0,0 +1,225 @@
+//! Email verification and password reset.
+//!
+//! Both flows mail the user a random token. Only its SHA-256 hash is stored in
+//! the "account_tokens" collection, every token expires and redeeming one
+//! deletes it, so a token works exactly once.
+//!
+//! The links in the messages open pages of the frontend at `mail.public_url`,
+//! not this service. Those pages post the token to `/verify_email` or
+//! `/password_reset/confirm`, so mail scanners following links can't redeem it.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::config::MailConfig;
+use crate::jwt::{hash_token, random_string};
+use crate::mail::{Email, FileMailer, MailError, Mailer, SmtpMailer};
+use crate::models::UserRecord;
+use crate::repository::RepositoryResult;
+
+pub const ACCOUNT_TOKENS_COLL_NAME: &str = "account_tokens";
+const VERIFY_EMAIL_TTL_SECS: i64 = 60 * 60 * 24;
+// Reset links are short-lived, as they grant access to the account
+const RESET_PASSWORD_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl TokenPurpose {
+    fn ttl_secs(self) -> i64 {
+        match self {
+            TokenPurpose::VerifyEmail => VERIFY_EMAIL_TTL_SECS,
+            TokenPurpose::ResetPassword => RESET_PASSWORD_TTL_SECS,
+        }
+    }
+}
+
+/// A mailed token as stored in the "account_tokens" collection.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AccountToken {
+    /// SHA-256 of the token, hex encoded
+    #[serde(rename = "_id")]
+    pub hash: String,
+    pub purpose: TokenPurpose,
+    pub user_id: ObjectId,
+    /// The address the token was mailed to
+    pub email: String,
+    pub expires_at: DateTime,
+}
+
+/// Storage of the mailed tokens.
+#[async_trait]
+pub trait AccountTokenStore: Send + Sync {
+    async fn insert(&self, token: &AccountToken) -> RepositoryResult<()>;
+
+    /// Atomically removes an unexpired token and returns it, so only one caller can redeem it.
+    async fn take(&self, hash: &str, purpose: TokenPurpose) -> RepositoryResult<Option<AccountToken>>;
+
+    /// Removes every token of `user_id` for `purpose`.
+    async fn delete_for_user(&self, user_id: ObjectId, purpose: TokenPurpose) -> RepositoryResult<()>;
+}
+
+/// Tokens stored in the "account_tokens" collection.
+pub struct MongoAccountTokenStore {
+    collection: Collection<AccountToken>,
+}
+
+impl MongoAccountTokenStore {
+    pub fn new(db: &Database) -> Self {
+        MongoAccountTokenStore {
+            collection: db.collection(ACCOUNT_TOKENS_COLL_NAME),
+        }
+    }
+}
+
+#[async_trait]
+impl AccountTokenStore for MongoAccountTokenStore {
+    async fn insert(&self, token: &AccountToken) -> RepositoryResult<()> {
+        self.collection.insert_one(token, None).await?;
+        Ok(())
+    }
+
+    async fn take(&self, hash: &str, purpose: TokenPurpose) -> RepositoryResult<Option<AccountToken>> {
+        let purpose = mongodb::bson::to_bson(&purpose).expect("a purpose always serializes");
+        Ok(self
+            .collection
+            .find_one_and_delete(
+                doc! { "_id": hash, "purpose": purpose, "expires_at": { "$gt": DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId, purpose: TokenPurpose) -> RepositoryResult<()> {
+        let purpose = mongodb::bson::to_bson(&purpose).expect("a purpose always serializes");
+        self.collection
+            .delete_many(doc! { "user_id": user_id, "purpose": purpose }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Tokens kept in memory, for tests and running without a database.
+#[derive(Default)]
+pub struct InMemoryAccountTokenStore {
+    tokens: Mutex<HashMap<String, AccountToken>>,
+}
+
+impl InMemoryAccountTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl AccountTokenStore for InMemoryAccountTokenStore {
+    async fn insert(&self, token: &AccountToken) -> RepositoryResult<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.insert(token.hash.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn take(&self, hash: &str, purpose: TokenPurpose) -> RepositoryResult<Option<AccountToken>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        match tokens.get(hash) {
+            Some(token) if token.purpose == purpose && token.expires_at > DateTime::now() => {
+                Ok(tokens.remove(hash))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId, purpose: TokenPurpose) -> RepositoryResult<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| token.user_id != user_id || token.purpose != purpose);
+        Ok(())
+    }
+}
+
+/// Creates a token for `user` and returns it; only its hash is stored.
+///
+/// Any older token of the same purpose stops working.
+pub async fn issue_token(store: &dyn AccountTokenStore, purpose: TokenPurpose, user: &UserRecord) -> RepositoryResult<String> {
+    store.delete_for_user(user.id, purpose).await?;
+    let token = random_string(48);
+    let expires_at = DateTime::now().timestamp_millis() + purpose.ttl_secs() * 1000;
+    store
+        .insert(&AccountToken {
+            hash: hash_token(&token),
+            purpose,
+            user_id: user.id,
+            email: user.email.clone(),
+            expires_at: DateTime::from_millis(expires_at),
+        })
+        .await?;
+    Ok(token)
+}
+
+/// Redeems a token, returning what it was issued for if it was valid.
+pub async fn redeem_token(store: &dyn AccountTokenStore, purpose: TokenPurpose, token: &str) -> RepositoryResult<Option<AccountToken>> {
+    store.take(&hash_token(token), purpose).await
+}
+
+/// Writes and sends the messages of both flows.
+pub struct AccountEmails {
+    mailer: Arc<dyn Mailer>,
+    /// The frontend the links in the messages open
+    base_url: String,
+}
+
+impl AccountEmails {
+    pub fn new(mailer: Arc<dyn Mailer>, base_url: impl Into<String>) -> Self {
+        AccountEmails {
+            mailer,
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// Sends through `mail.smtp_url` if it is set, otherwise writes the
+    /// messages to `mail.outbox_dir`.
+    pub fn from_config(config: &MailConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mailer: Arc<dyn Mailer> = match (&config.smtp_url, &config.from) {
+            (Some(url), Some(from)) => Arc::new(SmtpMailer::new(url, from)?),
+            (Some(_), None) => return Err("mail.from is required with mail.smtp_url".into()),
+            (None, _) => Arc::new(FileMailer::new(&config.outbox_dir)),
+        };
+        let base_url = config.public_url.as_deref().ok_or("mail.public_url is required")?;
+        Ok(AccountEmails::new(mailer, base_url))
+    }
+
+    pub async fn send_verification(&self, user: &UserRecord, token: &str) -> Result<(), MailError> {
+        self.mailer
+            .send(Email {
+                to: user.email.clone(),
+                subject: "Verify your email address".to_owned(),
+                body: format!(
+                    "Hi {},\n\nconfirm your email address by opening {}/verify_email?token={} \
+                     within 24 hours.\n",
+                    user.first_name, self.base_url, token
+                ),
+            })
+            .await
+    }
+
+    pub async fn send_password_reset(&self, user: &UserRecord, token: &str) -> Result<(), MailError> {
+        self.mailer
+            .send(Email {
+                to: user.email.clone(),
+                subject: "Reset your password".to_owned(),
+                body: format!(
+                    "Hi {},\n\nchoose a new password by opening {}/password_reset?token={} \
+                     within an hour. If you didn't ask for this, you can ignore this message.\n",
+                    user.first_name, self.base_url, token
+                ),
+            })
+            .await
+    }
+}
@@ -0,0 +1,141 @@
+//! Append-only trail of what happened to each account.
+//!
+//! Events go to the "user_audit" collection and are never changed or removed,
+//! not even when the account they describe is purged.
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::models::UserRecord;
+use crate::repository::RepositoryResult;
+use crate::session::SignedInUser;
+
+pub const USER_AUDIT_COLL_NAME: &str = "user_audit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Created,
+    Edited,
+    PasswordChanged,
+    RoleChanged,
+    SignedIn,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+/// One entry of the trail.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub action: AuditAction,
+    /// The account the event is about
+    pub user_id: ObjectId,
+    pub username: String,
+    /// Who did it; `None` for the service itself, e.g. when purging
+    pub actor_id: Option<ObjectId>,
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub at: DateTime,
+}
+
+impl AuditEvent {
+    /// An event about `user`, done by the service itself.
+    pub fn new(action: AuditAction, user: &UserRecord) -> Self {
+        AuditEvent {
+            id: ObjectId::new(),
+            action,
+            user_id: user.id,
+            username: user.username.clone(),
+            actor_id: None,
+            actor: None,
+            ip: None,
+            at: DateTime::now(),
+        }
+    }
+
+    /// Sets the signed in user as the one who did it.
+    pub fn by(mut self, actor: &SignedInUser) -> Self {
+        self.actor_id = Some(actor.user_id);
+        self.actor = Some(actor.username.clone());
+        self
+    }
+
+    /// Sets the user the event is about as the one who did it.
+    pub fn by_self(mut self) -> Self {
+        self.actor_id = Some(self.user_id);
+        self.actor = Some(self.username.clone());
+        self
+    }
+
+    pub fn with_ip(mut self, ip: Option<String>) -> Self {
+        self.ip = ip;
+        self
+    }
+}
+
+/// Storage of the trail. There is deliberately no way to change or remove events.
+#[async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn append(&self, event: &AuditEvent) -> RepositoryResult<()>;
+}
+
+/// Events stored in the "user_audit" collection.
+pub struct MongoAuditLog {
+    collection: Collection<AuditEvent>,
+}
+
+impl MongoAuditLog {
+    pub fn new(db: &Database) -> Self {
+        MongoAuditLog {
+            collection: db.collection(USER_AUDIT_COLL_NAME),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditLog for MongoAuditLog {
+    async fn append(&self, event: &AuditEvent) -> RepositoryResult<()> {
+        self.collection.insert_one(event, None).await?;
+        Ok(())
+    }
+}
+
+/// Events kept in memory, for tests and running without a database.
+#[derive(Default)]
+pub struct InMemoryAuditLog {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl InMemoryAuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every event so far, oldest first.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl AuditLog for InMemoryAuditLog {
+    async fn append(&self, event: &AuditEvent) -> RepositoryResult<()> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+/// Appends an event. A failure is logged rather than failing the request that
+/// already happened.
+pub async fn record(log: &dyn AuditLog, event: AuditEvent) {
+    if let Err(err) = log.append(&event).await {
+        eprintln!("recording {:?} of {} failed: {err}", event.action, event.username);
+    }
+}
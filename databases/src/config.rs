@@ -0,0 +1,586 @@
+//! Settings of the service.
+//!
+//! Every setting has a default, which a TOML file, then environment variables
+//! and finally command line flags can override. The result is checked once at
+//! startup, and all problems are reported together instead of one per restart.
+//!
+//! ```toml
+//! [server]
+//! bind = "0.0.0.0:8080"
+//! workers = 4
+//!
+//! [database]
+//! uri = "mongodb://localhost:27017"
+//! name = "myApp"
+//!
+//! [password]
+//! algorithm = "bcrypt"
+//! bcrypt_cost = 12
+//!
+//! [mail]
+//! public_url = "https://app.example.com"
+//!
+//! [cors]
+//! allowed_origins = ["https://app.example.com", "regex:https://pr-\\d+\\.example\\.com"]
+//! ```
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::Parser;
+use lettre::message::Mailbox;
+use serde::Deserialize;
+
+// Read when neither `--config` nor `CONFIG_FILE` names a file, if it exists
+const DEFAULT_CONFIG_FILE: &str = "databases.toml";
+
+/// Command line flags; they take precedence over the file and the environment.
+#[derive(Debug, Default, Parser)]
+#[command(about = "User accounts service backed by MongoDB")]
+pub struct Cli {
+    /// TOML file to read settings from [default: databases.toml, if it exists]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, e.g. 0.0.0.0:8080
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// Number of worker threads [default: one per CPU core]
+    #[arg(long)]
+    pub workers: Option<usize>,
+    /// Connection string of the MongoDB deployment
+    #[arg(long, value_name = "URI")]
+    pub mongodb_uri: Option<String>,
+    /// Name of the MongoDB database
+    #[arg(long, value_name = "NAME")]
+    pub db_name: Option<String>,
+    /// argon2id or bcrypt
+    #[arg(long, value_name = "ALGORITHM")]
+    pub password_algorithm: Option<PasswordAlgorithm>,
+    /// Cost of new bcrypt hashes, 4 to 31
+    #[arg(long, value_name = "COST")]
+    pub bcrypt_cost: Option<u32>,
+    /// Apply the pending database migrations and exit, e.g. from a deployment pipeline
+    #[arg(long)]
+    pub migrate_only: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub jwt: JwtConfig,
+    pub password: PasswordConfig,
+    pub mail: MailConfig,
+    pub accounts: AccountsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// One per CPU core if unset
+    pub workers: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "127.0.0.1:8080".to_owned(),
+            workers: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Required; there is no sensible default
+    pub uri: Option<String>,
+    pub name: String,
+    pub users_collection: String,
+    /// How long to wait for the database before giving up on an operation
+    /// or a readiness check
+    pub timeout_secs: u64,
+    /// Tries to connect at startup before giving up
+    pub connect_attempts: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            uri: None,
+            name: "myApp".to_owned(),
+            users_collection: "users".to_owned(),
+            timeout_secs: 5,
+            connect_attempts: 10,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Encrypts the session cookie; at least 64 bytes. Without one a random
+    /// key is generated, and sessions don't survive a restart.
+    pub key: Option<String>,
+    /// Only send the session cookie over HTTPS; turn off for local
+    /// development over plain HTTP
+    pub secure_cookie: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            key: None,
+            secure_cookie: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum JwtAlgorithm {
+    #[default]
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+impl FromStr for JwtAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HS256" => Ok(JwtAlgorithm::Hs256),
+            "RS256" => Ok(JwtAlgorithm::Rs256),
+            other => Err(format!("expected HS256 or RS256, not {other}")),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// Signs HS256 tokens. Without one a random secret is generated, and
+    /// tokens don't survive a restart.
+    pub secret: Option<String>,
+    /// PEM files, required for RS256
+    pub private_key_file: Option<PathBuf>,
+    pub public_key_file: Option<PathBuf>,
+    pub issuer: String,
+    pub audience: String,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            algorithm: JwtAlgorithm::default(),
+            secret: None,
+            private_key_file: None,
+            public_key_file: None,
+            issuer: "databases".to_owned(),
+            audience: "databases-api".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+}
+
+impl FromStr for PasswordAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(PasswordAlgorithm::Argon2id),
+            "bcrypt" => Ok(PasswordAlgorithm::Bcrypt),
+            other => Err(format!("expected argon2id or bcrypt, not {other}")),
+        }
+    }
+}
+
+/// How new password hashes are made. The Argon2id defaults are OWASP's recommendation.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub algorithm: PasswordAlgorithm,
+    pub bcrypt_cost: u32,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            algorithm: PasswordAlgorithm::default(),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    /// A lettre connection URL; without one mail is written to `outbox_dir`
+    pub smtp_url: Option<String>,
+    /// Sender address, required with `smtp_url`
+    pub from: Option<String>,
+    pub outbox_dir: PathBuf,
+    /// Required; the frontend the links in the messages open. It serves the
+    /// /verify_email and /password_reset pages, which take the `token` from
+    /// the query and post it to this service.
+    pub public_url: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            smtp_url: None,
+            from: None,
+            outbox_dir: PathBuf::from("outbox"),
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// Days a deleted user can be restored before it is purged
+    pub retention_days: u32,
+    /// Made an admin at startup, so there is someone to hand out roles
+    pub initial_admin: Option<String>,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            retention_days: 30,
+            initial_admin: None,
+        }
+    }
+}
+
+/// Requests allowed per client IP on the endpoints worth hammering.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// On /sign_in and /token, on top of the per-account lockout
+    pub sign_in_per_minute: u32,
+    pub sign_up_per_hour: u32,
+    /// On the endpoints that send verification and password reset emails
+    pub emails_per_hour: u32,
+    /// Take the client IP from the Forwarded or X-Forwarded-For headers;
+    /// only behind a reverse proxy that sets them
+    pub trust_proxy: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            sign_in_per_minute: 10,
+            sign_up_per_hour: 20,
+            emails_per_hour: 10,
+            trust_proxy: false,
+        }
+    }
+}
+
+/// Browser frontends allowed to call the service from other origins.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Exact, e.g. "https://app.example.com", every subdomain with
+    /// "https://*.example.com", a pattern after "regex:", or "*" for any
+    pub allowed_origins: Vec<String>,
+    /// Let those origins send the session cookie
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+/// Every problem found while loading the settings.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        self.0.iter().try_for_each(|problem| write!(f, "\n  - {problem}"))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Overrides settings with the environment variables that are set, noting the
+// ones that don't parse
+struct Env<'a, F> {
+    var: F,
+    problems: &'a mut Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Env<'_, F> {
+    fn parse<T: FromStr>(&mut self, name: &str) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        let value = (self.var)(name)?;
+        value
+            .parse()
+            .map_err(|err| self.problems.push(format!("{name}: {err}")))
+            .ok()
+    }
+
+    fn set<T: FromStr>(&mut self, name: &str, target: &mut T)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.parse(name) {
+            *target = value;
+        }
+    }
+
+    fn set_some<T: FromStr>(&mut self, name: &str, target: &mut Option<T>)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.parse(name) {
+            *target = Some(value);
+        }
+    }
+}
+
+impl Config {
+    /// Loads the settings from the file, the process environment and `cli`.
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let path = cli
+            .config
+            .clone()
+            .or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from));
+        let file = match &path {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map(|contents| (path.display().to_string(), contents))
+                    .map_err(|err| ConfigError(vec![format!("{}: {err}", path.display())]))?,
+            ),
+            None => std::fs::read_to_string(DEFAULT_CONFIG_FILE)
+                .ok()
+                .map(|contents| (DEFAULT_CONFIG_FILE.to_owned(), contents)),
+        };
+        let file = file.as_ref().map(|(name, contents)| (name.as_str(), contents.as_str()));
+        Self::from_sources(file, |name| std::env::var(name).ok(), cli)
+    }
+
+    /// Layers `file` (its name and contents), `env` and `cli` over the defaults.
+    pub fn from_sources(
+        file: Option<(&str, &str)>,
+        env: impl Fn(&str) -> Option<String>,
+        cli: Cli,
+    ) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let mut config = match file.map(|(name, contents)| (name, toml::from_str::<Config>(contents))) {
+            None => Config::default(),
+            Some((_, Ok(config))) => config,
+            Some((name, Err(err))) => {
+                // Keep going with the defaults, to find the other problems too
+                problems.push(format!("{name}: {}", err.message()));
+                Config::default()
+            }
+        };
+
+        let mut env = Env { var: env, problems: &mut problems };
+        env.set("BIND_ADDRESS", &mut config.server.bind);
+        env.set_some("WORKERS", &mut config.server.workers);
+        env.set_some("MONGODB_URI", &mut config.database.uri);
+        env.set("DB_NAME", &mut config.database.name);
+        env.set("USERS_COLLECTION", &mut config.database.users_collection);
+        env.set("DB_TIMEOUT_SECS", &mut config.database.timeout_secs);
+        env.set("DB_CONNECT_ATTEMPTS", &mut config.database.connect_attempts);
+        env.set_some("SESSION_KEY", &mut config.session.key);
+        env.set("SESSION_COOKIE_SECURE", &mut config.session.secure_cookie);
+        env.set("JWT_ALGORITHM", &mut config.jwt.algorithm);
+        env.set_some("JWT_SECRET", &mut config.jwt.secret);
+        env.set_some("JWT_PRIVATE_KEY_FILE", &mut config.jwt.private_key_file);
+        env.set_some("JWT_PUBLIC_KEY_FILE", &mut config.jwt.public_key_file);
+        env.set("JWT_ISSUER", &mut config.jwt.issuer);
+        env.set("JWT_AUDIENCE", &mut config.jwt.audience);
+        env.set("PASSWORD_ALGORITHM", &mut config.password.algorithm);
+        env.set("BCRYPT_COST", &mut config.password.bcrypt_cost);
+        env.set("ARGON2_MEMORY_KIB", &mut config.password.argon2_memory_kib);
+        env.set("ARGON2_ITERATIONS", &mut config.password.argon2_iterations);
+        env.set("ARGON2_PARALLELISM", &mut config.password.argon2_parallelism);
+        env.set_some("SMTP_URL", &mut config.mail.smtp_url);
+        env.set_some("MAIL_FROM", &mut config.mail.from);
+        env.set("MAIL_OUTBOX_DIR", &mut config.mail.outbox_dir);
+        env.set_some("PUBLIC_URL", &mut config.mail.public_url);
+        env.set("USER_RETENTION_DAYS", &mut config.accounts.retention_days);
+        env.set_some("INITIAL_ADMIN", &mut config.accounts.initial_admin);
+        env.set("RATE_LIMIT_SIGN_IN_PER_MINUTE", &mut config.rate_limit.sign_in_per_minute);
+        env.set("RATE_LIMIT_SIGN_UP_PER_HOUR", &mut config.rate_limit.sign_up_per_hour);
+        env.set("RATE_LIMIT_EMAILS_PER_HOUR", &mut config.rate_limit.emails_per_hour);
+        env.set("TRUST_PROXY", &mut config.rate_limit.trust_proxy);
+        // Comma separated, as a variable holds no list
+        if let Some(origins) = env.parse::<String>("CORS_ALLOWED_ORIGINS") {
+            config.cors.allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        env.set("CORS_ALLOW_CREDENTIALS", &mut config.cors.allow_credentials);
+        env.set("CORS_MAX_AGE_SECS", &mut config.cors.max_age_secs);
+
+        let Cli {
+            config: _,
+            bind,
+            workers,
+            mongodb_uri,
+            db_name,
+            password_algorithm,
+            bcrypt_cost,
+            migrate_only: _,
+        } = cli;
+        config.server.bind = bind.unwrap_or(config.server.bind);
+        config.server.workers = workers.or(config.server.workers);
+        config.database.uri = mongodb_uri.or(config.database.uri);
+        config.database.name = db_name.unwrap_or(config.database.name);
+        config.password.algorithm = password_algorithm.unwrap_or(config.password.algorithm);
+        config.password.bcrypt_cost = bcrypt_cost.unwrap_or(config.password.bcrypt_cost);
+
+        problems.extend(config.problems());
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    // Settings that parsed but can't work
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_owned());
+            }
+        };
+
+        check(
+            self.server.bind.to_socket_addrs().is_ok(),
+            "server.bind must be a host and port, e.g. 127.0.0.1:8080",
+        );
+        check(self.server.workers != Some(0), "server.workers must be at least 1");
+
+        match &self.database.uri {
+            Some(uri) => check(
+                uri.starts_with("mongodb://") || uri.starts_with("mongodb+srv://"),
+                "database.uri must start with mongodb:// or mongodb+srv://",
+            ),
+            None => check(false, "database.uri is required (or set MONGODB_URI)"),
+        }
+        check(!self.database.name.is_empty(), "database.name must not be empty");
+        check(
+            !self.database.users_collection.is_empty(),
+            "database.users_collection must not be empty",
+        );
+        check(self.database.timeout_secs > 0, "database.timeout_secs must be at least 1");
+        check(self.database.connect_attempts > 0, "database.connect_attempts must be at least 1");
+
+        check(
+            self.session.key.as_ref().is_none_or(|key| key.len() >= 64),
+            "session.key must be at least 64 bytes",
+        );
+
+        if self.jwt.algorithm == JwtAlgorithm::Rs256 {
+            check(self.jwt.private_key_file.is_some(), "jwt.private_key_file is required for RS256");
+            check(self.jwt.public_key_file.is_some(), "jwt.public_key_file is required for RS256");
+        }
+
+        match self.password.algorithm {
+            PasswordAlgorithm::Bcrypt => check(
+                (4..=31).contains(&self.password.bcrypt_cost),
+                "password.bcrypt_cost must be between 4 and 31",
+            ),
+            PasswordAlgorithm::Argon2id => {
+                let params = argon2::Params::new(
+                    self.password.argon2_memory_kib,
+                    self.password.argon2_iterations,
+                    self.password.argon2_parallelism,
+                    None,
+                );
+                if let Err(err) = params {
+                    check(false, &format!("password.argon2_* settings are invalid: {err}"));
+                }
+            }
+        }
+
+        if self.mail.smtp_url.is_some() {
+            match &self.mail.from {
+                Some(from) => check(
+                    from.parse::<Mailbox>().is_ok(),
+                    "mail.from must be an email address",
+                ),
+                None => check(false, "mail.from is required with mail.smtp_url"),
+            }
+        }
+        match &self.mail.public_url {
+            Some(url) => check(
+                url.starts_with("http://") || url.starts_with("https://"),
+                "mail.public_url must be an http or https URL",
+            ),
+            None => check(false, "mail.public_url is required (or set PUBLIC_URL)"),
+        }
+
+        let limits = &self.rate_limit;
+        check(
+            limits.sign_in_per_minute > 0 && limits.sign_up_per_hour > 0 && limits.emails_per_hour > 0,
+            "rate_limit.* must each be at least 1",
+        );
+
+        for origin in &self.cors.allowed_origins {
+            match origin.strip_prefix("regex:") {
+                Some(pattern) => {
+                    if let Err(err) = regex::Regex::new(pattern) {
+                        check(false, &format!("cors.allowed_origins: {origin} is not a valid regex: {err}"));
+                    }
+                }
+                None => check(
+                    origin == "*"
+                        || (origin.starts_with("http://") || origin.starts_with("https://")) && !origin.ends_with('/'),
+                    &format!("cors.allowed_origins: {origin} must be a scheme and host, e.g. https://app.example.com"),
+                ),
+            }
+        }
+        check(
+            !(self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|origin| origin == "*")),
+            "cors.allow_credentials can't be used with any origin (\"*\")",
+        );
+
+        problems
+    }
+}
@@ -0,0 +1,60 @@
+//! Whether the service can reach its database, for the readiness probe.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use mongodb::bson::doc;
+use mongodb::Database;
+
+use crate::repository::{RepositoryError, RepositoryResult};
+
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// Succeeds if the storage backend answers in time.
+    async fn check(&self) -> RepositoryResult<()>;
+}
+
+/// Pings the MongoDB deployment.
+pub struct MongoHealthCheck {
+    db: Database,
+    timeout: Duration,
+}
+
+impl MongoHealthCheck {
+    pub fn new(db: &Database, timeout: Duration) -> Self {
+        MongoHealthCheck {
+            db: db.clone(),
+            timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for MongoHealthCheck {
+    async fn check(&self) -> RepositoryResult<()> {
+        let ping = self.db.run_command(doc! { "ping": 1 }, None);
+        match actix_web::rt::time::timeout(self.timeout, ping).await {
+            Ok(result) => result.map(|_| ()).map_err(RepositoryError::from),
+            Err(_) => {
+                let timed_out = std::io::Error::from(std::io::ErrorKind::TimedOut);
+                Err(RepositoryError::Unavailable(timed_out.into()))
+            }
+        }
+    }
+}
+
+/// Always healthy, as there is no database to lose.
+#[derive(Default)]
+pub struct InMemoryHealthCheck;
+
+impl InMemoryHealthCheck {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl HealthCheck for InMemoryHealthCheck {
+    async fn check(&self) -> RepositoryResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+//! Bearer-token authentication for clients that can't use the session cookie.
+//!
+//! Access tokens are short-lived JWTs (HS256 or RS256). Refresh tokens are
+//! random strings stored hashed in a `TokenStore` and rotated on every use;
+//! presenting an already rotated refresh token revokes its whole family.
+//! Access tokens can be revoked before they expire by putting their `jti` on a
+//! denylist.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use async_trait::async_trait;
+use errors::AppError;
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{JwtAlgorithm, JwtConfig};
+use crate::repository::RepositoryResult;
+
+pub const REFRESH_TOKENS_COLL_NAME: &str = "refresh_tokens";
+pub const REVOKED_TOKENS_COLL_NAME: &str = "revoked_tokens";
+// Lifetime of an access token
+pub const ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
+// Lifetime of a refresh token
+const REFRESH_TOKEN_TTL_SECS: i64 = 60 * 60 * 24 * 30;
+
+/// Claims carried by an access token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Claims {
+    /// Hex encoded id of the user
+    pub sub: String,
+    pub username: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    /// Unique token id, used for revocation
+    pub jti: String,
+}
+
+/// Keys and validation settings used to sign and verify access tokens.
+#[derive(Clone)]
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    issuer: String,
+    audience: String,
+}
+
+impl JwtKeys {
+    /// Keys for HS256 with a shared secret.
+    pub fn hs256(secret: &[u8], issuer: &str, audience: &str) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            issuer: issuer.to_owned(),
+            audience: audience.to_owned(),
+        }
+    }
+
+    /// Keys for RS256 read from a PEM encoded private and public key.
+    pub fn rs256_from_pem_files(
+        private_key: impl AsRef<Path>,
+        public_key: impl AsRef<Path>,
+        issuer: &str,
+        audience: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let private_pem = std::fs::read(private_key)?;
+        let public_pem = std::fs::read(public_key)?;
+        Ok(JwtKeys {
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(&private_pem)?,
+            decoding: DecodingKey::from_rsa_pem(&public_pem)?,
+            issuer: issuer.to_owned(),
+            audience: audience.to_owned(),
+        })
+    }
+
+    /// Loads the configured keys.
+    ///
+    /// Without a secret HS256 uses a random one, so tokens don't survive a restart.
+    pub fn from_config(config: &JwtConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        match config.algorithm {
+            JwtAlgorithm::Rs256 => {
+                let (Some(private_key), Some(public_key)) = (&config.private_key_file, &config.public_key_file) else {
+                    return Err("RS256 needs jwt.private_key_file and jwt.public_key_file".into());
+                };
+                Self::rs256_from_pem_files(private_key, public_key, &config.issuer, &config.audience)
+            }
+            JwtAlgorithm::Hs256 => {
+                let secret = config.secret.clone().unwrap_or_else(|| random_string(64));
+                Ok(Self::hs256(secret.as_bytes(), &config.issuer, &config.audience))
+            }
+        }
+    }
+
+    /// Signs a new access token for the given user.
+    pub fn issue(&self, user_id: ObjectId, username: &str) -> jsonwebtoken::errors::Result<String> {
+        let iat = DateTime::now().timestamp_millis() / 1000;
+        let claims = Claims {
+            sub: user_id.to_hex(),
+            username: username.to_owned(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat,
+            exp: iat + ACCESS_TOKEN_TTL_SECS,
+            jti: random_string(32),
+        };
+        encode(&Header::new(self.algorithm), &claims, &self.encoding)
+    }
+
+    /// Checks signature, expiry, issuer and audience of an access token.
+    pub fn validate(&self, token: &str) -> jsonwebtoken::errors::Result<Claims> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        decode::<Claims>(token, &self.decoding, &validation).map(|data| data.claims)
+    }
+}
+
+/// A random alphanumeric string of `len` characters.
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex encoded SHA-256 of a token; only the hash is ever stored.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// A refresh token as stored in the "refresh_tokens" collection.
+///
+/// Only the SHA-256 hash of the token is kept, never the token itself.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefreshToken {
+    #[serde(rename = "_id")]
+    pub hash: String,
+    pub family: String,
+    pub user_id: ObjectId,
+    pub username: String,
+    pub issued_at: DateTime,
+    pub expires_at: DateTime,
+    pub rotated: bool,
+}
+
+/// A denylisted access token; kept until the token would have expired anyway.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevokedToken {
+    #[serde(rename = "_id")]
+    pub jti: String,
+    pub expires_at: DateTime,
+}
+
+/// Storage of refresh tokens and the access token denylist.
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> RepositoryResult<()>;
+
+    async fn find_refresh_token(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>>;
+
+    /// Atomically marks an unused, unexpired refresh token as rotated.
+    ///
+    /// Returns the token if it was usable, so only one caller can rotate it.
+    async fn mark_rotated(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>>;
+
+    async fn delete_family(&self, family: &str) -> RepositoryResult<()>;
+
+    /// Deletes every refresh token of a user.
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()>;
+
+    async fn deny(&self, token: &RevokedToken) -> RepositoryResult<()>;
+
+    async fn is_denied(&self, jti: &str) -> RepositoryResult<bool>;
+}
+
+/// Tokens stored in the "refresh_tokens" and "revoked_tokens" collections.
+pub struct MongoTokenStore {
+    refresh_tokens: Collection<RefreshToken>,
+    revoked_tokens: Collection<RevokedToken>,
+}
+
+impl MongoTokenStore {
+    pub fn new(db: &Database) -> Self {
+        MongoTokenStore {
+            refresh_tokens: db.collection(REFRESH_TOKENS_COLL_NAME),
+            revoked_tokens: db.collection(REVOKED_TOKENS_COLL_NAME),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStore for MongoTokenStore {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> RepositoryResult<()> {
+        self.refresh_tokens.insert_one(token, None).await?;
+        Ok(())
+    }
+
+    async fn find_refresh_token(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>> {
+        Ok(self.refresh_tokens.find_one(doc! { "_id": hash }, None).await?)
+    }
+
+    async fn mark_rotated(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>> {
+        Ok(self
+            .refresh_tokens
+            .find_one_and_update(
+                doc! { "_id": hash, "rotated": false, "expires_at": { "$gt": DateTime::now() } },
+                doc! { "$set": { "rotated": true } },
+                None,
+            )
+            .await?)
+    }
+
+    async fn delete_family(&self, family: &str) -> RepositoryResult<()> {
+        self.refresh_tokens
+            .delete_many(doc! { "family": family }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()> {
+        self.refresh_tokens
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn deny(&self, token: &RevokedToken) -> RepositoryResult<()> {
+        self.revoked_tokens.insert_one(token, None).await?;
+        Ok(())
+    }
+
+    async fn is_denied(&self, jti: &str) -> RepositoryResult<bool> {
+        Ok(self
+            .revoked_tokens
+            .find_one(doc! { "_id": jti }, None)
+            .await?
+            .is_some())
+    }
+}
+
+/// Tokens kept in memory, for tests and running without a database.
+#[derive(Default)]
+pub struct InMemoryTokenStore {
+    refresh_tokens: Mutex<HashMap<String, RefreshToken>>,
+    revoked_tokens: Mutex<HashMap<String, RevokedToken>>,
+}
+
+impl InMemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TokenStore for InMemoryTokenStore {
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> RepositoryResult<()> {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+        tokens.insert(token.hash.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn find_refresh_token(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>> {
+        Ok(self.refresh_tokens.lock().unwrap().get(hash).cloned())
+    }
+
+    async fn mark_rotated(&self, hash: &str) -> RepositoryResult<Option<RefreshToken>> {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+        match tokens.get_mut(hash) {
+            Some(token) if !token.rotated && token.expires_at > DateTime::now() => {
+                let current = token.clone();
+                token.rotated = true;
+                Ok(Some(current))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_family(&self, family: &str) -> RepositoryResult<()> {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+        tokens.retain(|_, token| token.family != family);
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()> {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+        tokens.retain(|_, token| token.user_id != user_id);
+        Ok(())
+    }
+
+    async fn deny(&self, token: &RevokedToken) -> RepositoryResult<()> {
+        let mut revoked = self.revoked_tokens.lock().unwrap();
+        revoked.insert(token.jti.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn is_denied(&self, jti: &str) -> RepositoryResult<bool> {
+        Ok(self.revoked_tokens.lock().unwrap().contains_key(jti))
+    }
+}
+
+async fn store_refresh_token(
+    store: &dyn TokenStore,
+    family: String,
+    user_id: ObjectId,
+    username: &str,
+) -> RepositoryResult<String> {
+    let token = random_string(64);
+    let issued_at = DateTime::now();
+    let record = RefreshToken {
+        hash: hash_token(&token),
+        family,
+        user_id,
+        username: username.to_owned(),
+        issued_at,
+        expires_at: DateTime::from_millis(
+            issued_at.timestamp_millis() + REFRESH_TOKEN_TTL_SECS * 1000,
+        ),
+        rotated: false,
+    };
+    store.insert_refresh_token(&record).await?;
+    Ok(token)
+}
+
+/// Issues the first refresh token of a new family after a password grant.
+pub async fn issue_refresh_token(
+    store: &dyn TokenStore,
+    user_id: ObjectId,
+    username: &str,
+) -> RepositoryResult<String> {
+    store_refresh_token(store, random_string(32), user_id, username).await
+}
+
+/// Exchanges a refresh token for a new one of the same family.
+///
+/// Returns the new token together with the user it belongs to, or `None` if
+/// the token is unknown, expired or was already used. Reusing a rotated token
+/// revokes every token of its family.
+pub async fn rotate_refresh_token(
+    store: &dyn TokenStore,
+    token: &str,
+) -> RepositoryResult<Option<(String, ObjectId, String)>> {
+    let hash = hash_token(token);
+    match store.mark_rotated(&hash).await? {
+        Some(current) => {
+            let new_token =
+                store_refresh_token(store, current.family, current.user_id, &current.username)
+                    .await?;
+            Ok(Some((new_token, current.user_id, current.username)))
+        }
+        None => {
+            if let Some(reused) = store.find_refresh_token(&hash).await? {
+                store.delete_family(&reused.family).await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Revokes the refresh token and every other token of its family.
+pub async fn revoke_refresh_token(store: &dyn TokenStore, token: &str) -> RepositoryResult<()> {
+    if let Some(record) = store.find_refresh_token(&hash_token(token)).await? {
+        store.delete_family(&record.family).await?;
+    }
+    Ok(())
+}
+
+/// Puts an access token on the denylist until it expires.
+pub async fn revoke_access_token(store: &dyn TokenStore, jti: &str, exp: i64) -> RepositoryResult<()> {
+    let record = RevokedToken {
+        jti: jti.to_owned(),
+        expires_at: DateTime::from_millis(exp * 1000),
+    };
+    store.deny(&record).await
+}
+
+/// Returns the token of an `Authorization: Bearer` header, if there is one.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))
+        .map(str::trim)
+}
+
+/// Validated claims of the request's bearer token.
+///
+/// Rejects the request with 401 Unauthorized if the header is missing, the
+/// token doesn't validate or it has been revoked.
+#[derive(Clone, Debug)]
+pub struct BearerToken(pub Claims);
+
+impl FromRequest for BearerToken {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let keys = req
+                .app_data::<web::Data<JwtKeys>>()
+                .ok_or_else(|| AppError::internal("JWT keys are not configured"))?;
+            let store = req
+                .app_data::<web::Data<dyn TokenStore>>()
+                .ok_or_else(|| AppError::internal("token store is not configured"))?;
+
+            let token = bearer_token(&req)
+                .ok_or_else(|| AppError::unauthorized("Missing bearer token"))?;
+            let claims = keys
+                .validate(token)
+                .map_err(|_| AppError::unauthorized("Invalid or expired access token"))?;
+            if store
+                .is_denied(&claims.jti)
+                .await?
+            {
+                return Err(AppError::unauthorized("Access token has been revoked"));
+            }
+            Ok(BearerToken(claims))
+        })
+    }
+}
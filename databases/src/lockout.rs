@@ -0,0 +1,221 @@
+//! Brute-force protection for the sign-in endpoints.
+//!
+//! Failed attempts are counted per username and per client IP in a
+//! `LoginAttemptStore`. Once a counter reaches `MAX_FREE_ATTEMPTS`, further
+//! attempts are locked out for a period that doubles with every additional
+//! failure.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::repository::RepositoryResult;
+
+pub const LOGIN_ATTEMPTS_COLL_NAME: &str = "login_attempts";
+// Failures allowed before the first lockout
+const MAX_FREE_ATTEMPTS: i32 = 5;
+// Length of the first lockout, doubled for each failure after that
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+/// Counters without a new failure for this long are forgotten, by a TTL index.
+pub const ATTEMPTS_RESET_SECS: u64 = 60 * 60 * 24;
+
+/// Failed sign-in attempts for one username or one client IP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LoginAttempts {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub failures: i32,
+    pub last_failure: DateTime,
+    pub locked_until: Option<DateTime>,
+}
+
+/// Storage of the failed-attempt counters.
+#[async_trait]
+pub trait LoginAttemptStore: Send + Sync {
+    /// Returns the counters for `keys` that are locked past `now`.
+    async fn find_locked(&self, keys: &[&str], now: DateTime) -> RepositoryResult<Vec<LoginAttempts>>;
+
+    /// Increments the counter for `key`, creating it if needed, and returns it.
+    async fn increment(&self, key: &str, now: DateTime) -> RepositoryResult<LoginAttempts>;
+
+    async fn lock(&self, key: &str, until: DateTime) -> RepositoryResult<()>;
+
+    async fn clear(&self, keys: &[&str]) -> RepositoryResult<()>;
+}
+
+/// Counters stored in the "login_attempts" collection.
+pub struct MongoLoginAttemptStore {
+    collection: Collection<LoginAttempts>,
+}
+
+impl MongoLoginAttemptStore {
+    pub fn new(db: &Database) -> Self {
+        MongoLoginAttemptStore {
+            collection: db.collection(LOGIN_ATTEMPTS_COLL_NAME),
+        }
+    }
+}
+
+#[async_trait]
+impl LoginAttemptStore for MongoLoginAttemptStore {
+    async fn find_locked(&self, keys: &[&str], now: DateTime) -> RepositoryResult<Vec<LoginAttempts>> {
+        let mut cursor = self
+            .collection
+            .find(doc! { "_id": { "$in": keys }, "locked_until": { "$gt": now } }, None)
+            .await?;
+        let mut locked = Vec::new();
+        while cursor.advance().await? {
+            locked.push(cursor.deserialize_current()?);
+        }
+        Ok(locked)
+    }
+
+    async fn increment(&self, key: &str, now: DateTime) -> RepositoryResult<LoginAttempts> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let attempts = self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": key },
+                doc! {
+                    "$inc": { "failures": 1 },
+                    "$set": { "last_failure": now },
+                    "$setOnInsert": { "locked_until": null },
+                },
+                options,
+            )
+            .await?;
+        Ok(attempts.expect("upsert always returns the document"))
+    }
+
+    async fn lock(&self, key: &str, until: DateTime) -> RepositoryResult<()> {
+        self.collection
+            .update_one(doc! { "_id": key }, doc! { "$set": { "locked_until": until } }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self, keys: &[&str]) -> RepositoryResult<()> {
+        self.collection
+            .delete_many(doc! { "_id": { "$in": keys } }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Counters kept in memory, for tests and running without a database.
+#[derive(Default)]
+pub struct InMemoryLoginAttemptStore {
+    attempts: Mutex<HashMap<String, LoginAttempts>>,
+}
+
+impl InMemoryLoginAttemptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl LoginAttemptStore for InMemoryLoginAttemptStore {
+    async fn find_locked(&self, keys: &[&str], now: DateTime) -> RepositoryResult<Vec<LoginAttempts>> {
+        let attempts = self.attempts.lock().unwrap();
+        Ok(keys
+            .iter()
+            .filter_map(|key| attempts.get(*key))
+            .filter(|a| a.locked_until.is_some_and(|until| until > now))
+            .cloned()
+            .collect())
+    }
+
+    async fn increment(&self, key: &str, now: DateTime) -> RepositoryResult<LoginAttempts> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let entry = attempts.entry(key.to_owned()).or_insert_with(|| LoginAttempts {
+            key: key.to_owned(),
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        entry.failures += 1;
+        entry.last_failure = now;
+        Ok(entry.clone())
+    }
+
+    async fn lock(&self, key: &str, until: DateTime) -> RepositoryResult<()> {
+        if let Some(entry) = self.attempts.lock().unwrap().get_mut(key) {
+            entry.locked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, keys: &[&str]) -> RepositoryResult<()> {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(*key);
+        }
+        Ok(())
+    }
+}
+
+/// The counters a sign-in attempt is checked against.
+pub struct AttemptKeys {
+    username: String,
+    ip: Option<String>,
+}
+
+impl AttemptKeys {
+    pub fn new(username: &str, ip: Option<String>) -> Self {
+        AttemptKeys {
+            username: format!("user:{}", username.to_lowercase()),
+            ip: ip.map(|ip| format!("ip:{ip}")),
+        }
+    }
+
+    fn all(&self) -> Vec<&str> {
+        std::iter::once(self.username.as_str())
+            .chain(self.ip.as_deref())
+            .collect()
+    }
+}
+
+fn lockout_secs(failures: i32) -> i64 {
+    let exponent = (failures - MAX_FREE_ATTEMPTS).clamp(0, 16) as u32;
+    (BASE_LOCKOUT_SECS * 2_i64.pow(exponent)).min(MAX_LOCKOUT_SECS)
+}
+
+/// Returns how many seconds the caller has to wait if any counter is locked.
+pub async fn locked_for(store: &dyn LoginAttemptStore, keys: &AttemptKeys) -> RepositoryResult<Option<i64>> {
+    let now = DateTime::now();
+    let locked = store.find_locked(&keys.all(), now).await?;
+    Ok(locked
+        .iter()
+        .filter_map(|attempts| attempts.locked_until)
+        .map(|until| (until.timestamp_millis() - now.timestamp_millis() + 999) / 1000)
+        .max())
+}
+
+/// Counts a failed attempt against every key, locking the ones over the limit.
+pub async fn record_failure(store: &dyn LoginAttemptStore, keys: &AttemptKeys) -> RepositoryResult<()> {
+    for key in keys.all() {
+        let now = DateTime::now();
+        let attempts = store.increment(key, now).await?;
+        if attempts.failures >= MAX_FREE_ATTEMPTS {
+            let until = now.timestamp_millis() + lockout_secs(attempts.failures) * 1000;
+            store.lock(key, DateTime::from_millis(until)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Clears the username's counter after a successful sign in. The IP's is
+/// left to expire, or signing into an account of one's own between guesses
+/// would reset it.
+pub async fn record_success(store: &dyn LoginAttemptStore, keys: &AttemptKeys) -> RepositoryResult<()> {
+    store.clear(&[&keys.username]).await
+}
@@ -1,308 +1,1024 @@
 use std::future::{Ready, ready};
-use actix_web::{cookie, get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Result};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{delete, get, patch, post, put, web, App, Either, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer, Result};
+use actix_web::http::header;
+use actix_web::body::EitherBody;
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use mongodb::{bson::doc, options::IndexOptions, Client, Collection, IndexModel};
-use serde::{Deserialize, Serialize};
+use mongodb::{bson::DateTime, options::ClientOptions, Client, Database};
+use serde::Deserialize;
+use clap::Parser;
 use dotenv::dotenv;
-use regex::Regex;
+use errors::{AppError, AppResult, FieldError};
 use serde_json::json;
-use futures_util::future::{LocalBoxFuture, ok};
+use futures_util::future::LocalBoxFuture;
+use middleware::rate_limit::{ClientIp, InMemoryStore, Quota, RateLimit};
+use middleware::{Cors, Hooks};
 
-const DB_NAME: &str = "myApp";
-const COLL_NAME: &str = "users";
-
-// Defining User Struct
-#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-pub struct User {
-    pub first_name: String,
-    pub last_name: String,
-    pub username: String,
-    pub email: String,
-    pub password: String,
-    pub confirm_password: String,
-}
+mod account;
+mod audit;
+mod config;
+mod health;
+mod jwt;
+mod lockout;
+mod mail;
+mod migrations;
+mod models;
+mod pagination;
+mod password;
+mod repository;
+mod roles;
+mod session;
+mod validation;
 
+use account::{AccountEmails, AccountTokenStore, InMemoryAccountTokenStore, MongoAccountTokenStore, TokenPurpose};
+use audit::{AuditAction, AuditEvent, AuditLog, InMemoryAuditLog, MongoAuditLog};
+use config::{Cli, Config, CorsConfig, DatabaseConfig, RateLimitConfig};
+use health::{HealthCheck, InMemoryHealthCheck, MongoHealthCheck};
+use jwt::{InMemoryTokenStore, JwtKeys, MongoTokenStore, TokenStore};
+use lockout::{InMemoryLoginAttemptStore, LoginAttemptStore, MongoLoginAttemptStore};
+use models::{
+    ChangePasswordRequest, CreateUserRequest, EmailRequest, PublicProfile, ResetPasswordRequest, SetRoleRequest,
+    UpdateUserRequest, UserDetails, UserRecord, VerifyEmailRequest,
+};
+use pagination::{ListUsersParams, Page};
+use password::{HashAlgorithm, Passwords, Verification};
+use repository::{InMemoryUserRepository, MongoUserRepository, RepositoryError, RepositoryResult, UserRepository};
+use roles::{Action, Admin, Moderator, RequireRole, Role};
+use session::{Credential, InMemorySessionStore, MongoSessionStore, SessionKey, SessionStore, SignedInUser};
+use validation::Validated;
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-// pub struct IsUserSignedInMiddleware;
-
-// // Middleware factory is `Transform` trait
-// // `S` - type of the next service
-// // `B` - type of response's body
-// impl<S, B> Transform<S, ServiceRequest> for IsUserSignedInMiddleware
-// where
-//     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-//     S::Future: 'static,
-//     B: 'static,
-// {
-//     type Response = ServiceResponse<B>;
-//     type Error = Error;
-//     type InitError = ();
-//     type Transform = IsUserSignedInMiddlewareService<S>;
-//     type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-//     fn new_transform(&self, service: S) -> Self::Future {
-//         ready(Ok(IsUserSignedInMiddlewareService { service }))
-//     }
-// }
-
-// pub struct IsUserSignedInMiddlewareService<S> {
-//     service: S,
-// }
-
-// impl<S, B> Service<ServiceRequest> for IsUserSignedInMiddlewareService<S>
-// where
-//     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-//     S::Future: 'static,
-//     B: 'static,
-// {
-//     type Response = ServiceResponse<B>;
-//     type Error = Error;
-//     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
-
-//     forward_ready!(service);
-
-//     fn call(&self, req: ServiceRequest) -> Self::Future {
-//         println!("Hi from start. You requested: {}", req.path());
-
-        
-//         if let Some(cookie) = req.cookie("user") {
-//             let fut = self.service.call(req);
-//             Box::pin(async move {
-//                 let res = fut.await?;
-    
-//                 println!("Hi from response");
-//                 Ok(res)
-//             })
-//         }else {
-//             Box::pin(async {
-//                 Ok(req.into_response(
-//                     HttpResponse::TooManyRequests()
-//                         .finish()
-//                         .map_into_right_body(),
-//                 ))
-//             });
-//         }
-//     }
-// }
-
-//function for hashing password
-fn hash_password(password: &str) -> String {
-    hash(password, DEFAULT_COST).expect("Failed to hash password")
-}
-
-//function for verifying password while authenticating
-fn verify_password(password: &str, hash: &str) -> bool {
-    verify(password, hash).expect("Failed to verify password")
-}
-
-
-// Function to validate email format using a regular expression
-fn is_valid_email(email: &str) -> bool {
-    lazy_static::lazy_static! {
-        static ref EMAIL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$").unwrap();
-    }
-    EMAIL_REGEX.is_match(email)
+//
+// `IsUserSignedInMiddleware` guards every route of the scope it wraps, except
+// the ones in its allow-list. The signed-in user is resolved once here and put
+// into the request extensions, where the `SignedInUser` extractor picks it up.
+#[derive(Clone, Default)]
+pub struct IsUserSignedInMiddleware {
+    public_routes: Rc<Vec<String>>,
+}
+
+impl IsUserSignedInMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets requests for `path` through without a session.
+    ///
+    /// A trailing `*` matches every path starting with the given prefix.
+    pub fn allow(mut self, path: impl Into<String>) -> Self {
+        Rc::make_mut(&mut self.public_routes).push(path.into());
+        self
+    }
+}
+
+// Middleware factory is `Transform` trait
+// `S` - type of the next service
+// `B` - type of response's body
+impl<S, B> Transform<S, ServiceRequest> for IsUserSignedInMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IsUserSignedInMiddlewareService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IsUserSignedInMiddlewareService {
+            service: Rc::new(service),
+            public_routes: self.public_routes.clone(),
+        }))
+    }
+}
+
+pub struct IsUserSignedInMiddlewareService<S> {
+    service: Rc<S>,
+    public_routes: Rc<Vec<String>>,
+}
+
+impl<S> IsUserSignedInMiddlewareService<S> {
+    fn is_public(&self, path: &str) -> bool {
+        self.public_routes.iter().any(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        })
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for IsUserSignedInMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if self.is_public(req.path()) {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        let service = self.service.clone();
+        Box::pin(async move {
+            match SignedInUser::extract(req.request()).await {
+                Ok(user) => {
+                    req.extensions_mut().insert(user);
+                    Ok(service.call(req).await?.map_into_left_body())
+                }
+                Err(err) => Ok(req.error_response(err).map_into_right_body()),
+            }
+        })
+    }
 }
 
 /// Adds a new user to the "users" collection in the database.
+///
+/// The account can't sign in until its email address is verified.
 #[post("/add_user")]
-async fn add_user(client: web::Data<Client>, form: web::Form<User>) -> HttpResponse {
-    // Validate the email address
-    if !is_valid_email(&form.email) {
-        return HttpResponse::BadRequest().body("Invalid email format");
-    }
-    // Check if passwords match
-    if form.password != form.confirm_password {
-        return HttpResponse::BadRequest().body("Passwords do not match");
-    }
-    // Hash the new password before updating the user
-    let hashed_password = hash_password(&form.password);
-
-    // Create a new User with the hashed password
-    let user = User {
-        first_name: form.first_name.clone(),
-        last_name: form.last_name.clone(),
-        username: form.username.clone(),
-        email: form.email.clone(),
-        password: hashed_password,
-        confirm_password: String::new(), // Set to an empty string or handle it as needed
+async fn add_user(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    emails: web::Data<AccountEmails>,
+    audit: web::Data<dyn AuditLog>,
+    passwords: web::Data<Passwords>,
+    form: Validated<web::Form<CreateUserRequest>>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    // Hash the password; only the hash is stored, never `confirm_password`
+    let hashed_password = passwords.hash(&form.password).await?;
+    let user = UserRecord::new(form.into_inner().into_inner(), hashed_password);
+    users.create(&user).await?;
+
+    let event = AuditEvent::new(AuditAction::Created, &user).by_self().with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    send_verification(tokens.get_ref(), &emails, &user).await;
+    Ok(HttpResponse::Ok().body("user added"))
+}
+
+// Mails a fresh verification link. Failures are only logged, as the user can
+// ask for another link.
+async fn send_verification(tokens: &dyn AccountTokenStore, emails: &AccountEmails, user: &UserRecord) {
+    let sent = match account::issue_token(tokens, TokenPurpose::VerifyEmail, user).await {
+        Ok(token) => emails.send_verification(user, &token).await.map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
     };
+    if let Err(err) = sent {
+        eprintln!("sending the verification email to {} failed: {err}", user.username);
+    }
+}
 
-    let collection = client.database(DB_NAME).collection(COLL_NAME);
-    let result = collection.insert_one(user, None).await;
-    // Validate the email format before proceeding
-    match result {
-        Ok(_) => HttpResponse::Ok().body("user added"),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+fn invalid_token() -> AppError {
+    AppError::Validation(vec![
+        FieldError::new("token", "The link is invalid or has expired").with_code("invalid_token")
+    ])
+}
+
+/// Marks the address a verification token was mailed to as verified.
+#[post("/verify_email")]
+async fn verify_email(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    body: web::Json<VerifyEmailRequest>,
+) -> AppResult<HttpResponse> {
+    let token = account::redeem_token(tokens.get_ref(), TokenPurpose::VerifyEmail, &body.token)
+        .await?
+        .ok_or_else(invalid_token)?;
+    let mut record = match users.find_by_id(token.user_id).await? {
+        // The address changed after the link was sent
+        Some(record) if record.email == token.email => record,
+        _ => return Err(invalid_token()),
+    };
+    record.verify_email();
+    users.update(&record.username.clone(), &record).await?;
+    Ok(HttpResponse::Ok().json(json!({ "message": "Email verified" })))
+}
+
+/// Mails a new verification link to an unverified address.
+///
+/// Answers the same whether or not the address is known.
+#[post("/verify_email/resend")]
+async fn resend_verification(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    emails: web::Data<AccountEmails>,
+    body: Validated<web::Json<EmailRequest>>,
+) -> AppResult<HttpResponse> {
+    let user = users.find_by_email(&body.email).await?;
+    if let Some(user) = user.filter(|user| !user.email_verified) {
+        send_verification(tokens.get_ref(), &emails, &user).await;
     }
+    Ok(HttpResponse::Accepted().json(json!({
+        "message": "If the address belongs to an unverified account, a new link is on its way"
+    })))
 }
 
-/// Gets the user with the supplied username.
-#[get("/get_user/{username}")]
-async fn get_user(client: web::Data<Client>, username: web::Path<String>) -> HttpResponse {
-    let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    match collection
-        .find_one(doc! { "username": &username }, None)
-        .await
-    {
-        Ok(Some(user)) => HttpResponse::Ok().json(user),
-        Ok(None) => {
-            HttpResponse::NotFound().body(format!("No user found with username {username}"))
+/// Mails a password reset link.
+///
+/// Answers the same whether or not the address is known.
+#[post("/password_reset")]
+async fn request_password_reset(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    emails: web::Data<AccountEmails>,
+    body: Validated<web::Json<EmailRequest>>,
+) -> AppResult<HttpResponse> {
+    if let Some(user) = users.find_by_email(&body.email).await? {
+        let sent = match account::issue_token(tokens.get_ref(), TokenPurpose::ResetPassword, &user).await {
+            Ok(token) => emails.send_password_reset(&user, &token).await.map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = sent {
+            eprintln!("sending the password reset email to {} failed: {err}", user.username);
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
+    Ok(HttpResponse::Accepted().json(json!({
+        "message": "If the address belongs to an account, a reset link is on its way"
+    })))
+}
+
+/// Sets a new password with a reset token and signs the user out everywhere.
+#[post("/password_reset/confirm")]
+#[allow(clippy::too_many_arguments)]
+async fn confirm_password_reset(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    sessions: web::Data<dyn SessionStore>,
+    refresh_tokens: web::Data<dyn TokenStore>,
+    attempts: web::Data<dyn LoginAttemptStore>,
+    audit: web::Data<dyn AuditLog>,
+    passwords: web::Data<Passwords>,
+    body: Validated<web::Json<ResetPasswordRequest>>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let token = account::redeem_token(tokens.get_ref(), TokenPurpose::ResetPassword, &body.token)
+        .await?
+        .ok_or_else(invalid_token)?;
+    let mut record = match users.find_by_id(token.user_id).await? {
+        Some(record) if record.email == token.email => record,
+        _ => return Err(invalid_token()),
+    };
+    let hash = passwords.hash(&body.new_password).await?;
+    record.set_password_hash(hash);
+    // Receiving the link proves the address works
+    record.verify_email();
+
+    users.update(&record.username.clone(), &record).await?;
+    sessions.delete_for_user(record.id).await?;
+    refresh_tokens.delete_for_user(record.id).await?;
+    // A user locked out by someone guessing the password can sign in again
+    lockout::record_success(attempts.get_ref(), &lockout::AttemptKeys::new(&record.username, None)).await?;
+
+    let event = AuditEvent::new(AuditAction::PasswordChanged, &record)
+        .by_self()
+        .with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    Ok(HttpResponse::Ok().json(json!({ "message": "Password changed" })))
+}
+
+/// Liveness probe: answers as long as the process is running.
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
 }
 
-/// Gets the user with the supplied username.
-#[get("/delete_user/{username}")]
-async fn delete_user(client: web::Data<Client>, username: web::Path<String>) -> HttpResponse {
+/// Readiness probe: answers 200 only while the database responds in time.
+#[get("/readyz")]
+async fn readyz(health: web::Data<dyn HealthCheck>) -> HttpResponse {
+    match health.check().await {
+        Ok(()) => HttpResponse::Ok().json(json!({ "status": "ready" })),
+        Err(err) => {
+            eprintln!("readiness check failed: {err}");
+            HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, repository::RETRY_AFTER_SECS.to_string()))
+                .json(json!({ "status": "unavailable" }))
+        }
+    }
+}
+
+/// Gets the public profile of the user with the supplied username.
+#[get("/get_user/{username}")]
+async fn get_user(users: web::Data<dyn UserRepository>, username: web::Path<String>) -> AppResult<HttpResponse> {
     let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    match collection
-        .delete_one(doc! { "username": &username }, None)
-        .await
-    {    
-        Ok(result) => {
-            if result.deleted_count > 0 {
-                HttpResponse::Ok().body("User deleted successfully")
-            } else {
-                HttpResponse::NotFound().body(format!("No user found with username {}", username))
+    let user = users.find_by_username(&username).await?.ok_or_else(|| no_user(&username))?;
+    Ok(HttpResponse::Ok().json(PublicProfile::from(user)))
+}
+
+fn no_user(username: &str) -> AppError {
+    AppError::not_found(format!("No user found with username {username}"))
+}
+
+/// Lists users a page at a time, optionally filtered by a username or email prefix.
+#[get("")]
+async fn list_users(
+    users: web::Data<dyn UserRepository>,
+    params: web::Query<ListUsersParams>,
+    _moderator: RequireRole<Moderator>,
+) -> AppResult<HttpResponse> {
+    let query = params.into_inner().into_query()?;
+    let page = users.find_page(&query).await?;
+    Ok(HttpResponse::Ok().json(Page::new(&query, page)))
+}
+
+// Credentials posted to the sign in endpoints
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+// Right password, but the account's email address isn't verified yet
+fn email_not_verified() -> AppError {
+    AppError::forbidden("Verify your email address before signing in")
+}
+
+/// Checks the credentials, enforcing the failed-attempt lockout.
+///
+/// A password stored with an outdated algorithm or cost is rehashed on the way.
+async fn authenticate(
+    users: &dyn UserRepository,
+    attempts: &dyn LoginAttemptStore,
+    passwords: &Passwords,
+    credentials: &Credentials,
+    ip: Option<String>,
+) -> AppResult<UserRecord> {
+    let keys = lockout::AttemptKeys::new(&credentials.username, ip);
+    if let Some(secs) = lockout::locked_for(attempts, &keys).await? {
+        return Err(AppError::RateLimited {
+            retry_after_secs: secs.unsigned_abs(),
+        });
+    }
+
+    let user = users.find_by_username(&credentials.username).await?;
+    let verification = match &user {
+        Some(user) => passwords.verify(&credentials.password, &user.password_hash).await?,
+        None => {
+            passwords.verify_dummy(&credentials.password).await?;
+            Verification::Mismatch
+        }
+    };
+    match user {
+        Some(user) if verification != Verification::Mismatch => {
+            lockout::record_success(attempts, &keys).await?;
+            if verification == Verification::MatchOutdated {
+                return Ok(rehash(users, passwords, user, &credentials.password).await);
             }
+            Ok(user)
+        }
+        _ => {
+            lockout::record_failure(attempts, &keys).await?;
+            // Unknown username or wrong password; deliberately not told apart
+            Err(AppError::unauthorized("Invalid username or password"))
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
 }
 
+// Replaces the stored hash with one made with the current settings. Failing
+// is only logged, as the old hash still works.
+async fn rehash(users: &dyn UserRepository, passwords: &Passwords, user: UserRecord, password: &str) -> UserRecord {
+    let mut rehashed = user.clone();
+    let result = match passwords.hash(password).await {
+        Ok(hash) => {
+            rehashed.set_password_hash(hash);
+            users.update(&user.username, &rehashed).await.map_err(|err| err.to_string())
+        }
+        Err(err) => Err(err.to_string()),
+    };
+    match result {
+        Ok(_) => rehashed,
+        Err(err) => {
+            eprintln!("rehashing the password of {} failed: {err}", user.username);
+            user
+        }
+    }
+}
+
+// The peer address, not a forwarded header, so clients can't pick their own IP
+fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+/// Signs the user in with a JSON or form body and starts a session.
+#[post("/sign_in")]
+#[allow(clippy::too_many_arguments)]
+async fn sign_in(
+    users: web::Data<dyn UserRepository>,
+    attempts: web::Data<dyn LoginAttemptStore>,
+    sessions: web::Data<dyn SessionStore>,
+    audit: web::Data<dyn AuditLog>,
+    passwords: web::Data<Passwords>,
+    key: web::Data<SessionKey>,
+    config: web::Data<Config>,
+    credentials: Either<web::Json<Credentials>, web::Form<Credentials>>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let credentials = match credentials {
+        Either::Left(json) => json.into_inner(),
+        Either::Right(form) => form.into_inner(),
+    };
+    let user = authenticate(users.get_ref(), attempts.get_ref(), &passwords, &credentials, client_ip(&req)).await?;
+    if !user.email_verified {
+        return Err(email_not_verified());
+    }
+    let session = session::start_session(sessions.get_ref(), user.id, &user.username).await?;
+
+    let event = AuditEvent::new(AuditAction::SignedIn, &user).by_self().with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    Ok(HttpResponse::Ok()
+        .cookie(session::session_cookie(&key, &session, config.session.secure_cookie))
+        .json(json!({ "message": format!("Welcome {}", user.username) })))
+}
 
-//Get User Sign In
-#[get("/sign_in_user/{username}/{password}")]
-async fn sign_in_user(client: web::Data<Client>, path: web::Path<(String, String)>) -> HttpResponse {
-    let (username, password)  = path.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    match collection
-    .find_one(doc! {"username" : &username}, None)
-    .await {
-        Ok(Some(user)) => {
-            if verify_password(&password, &user.password) {
-                HttpResponse::Ok().cookie(cookie::Cookie::build("isloggedIn", "true").http_only(true).finish())
-                .body(format!("SignIn Succesfull Welcome User {username}"))
-            }else {
-                HttpResponse::NotFound().body(format!("Incorrect Password"))
+// Body of a token request, OAuth 2.0 style
+#[derive(Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+enum TokenRequest {
+    Password { username: String, password: String },
+    RefreshToken { refresh_token: String },
+}
+
+/// Issues a JWT access token and a refresh token for bearer authentication.
+///
+/// Clients either send their credentials (`grant_type=password`) or trade in
+/// a refresh token (`grant_type=refresh_token`), which is then rotated.
+#[post("/token")]
+#[allow(clippy::too_many_arguments)]
+async fn issue_token(
+    users: web::Data<dyn UserRepository>,
+    attempts: web::Data<dyn LoginAttemptStore>,
+    tokens: web::Data<dyn TokenStore>,
+    audit: web::Data<dyn AuditLog>,
+    passwords: web::Data<Passwords>,
+    keys: web::Data<JwtKeys>,
+    body: web::Json<TokenRequest>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let (user_id, username, refresh_token) = match body.into_inner() {
+        TokenRequest::Password { username, password } => {
+            let credentials = Credentials { username, password };
+            let user = authenticate(users.get_ref(), attempts.get_ref(), &passwords, &credentials, client_ip(&req)).await?;
+            if !user.email_verified {
+                return Err(email_not_verified());
             }
+            let event = AuditEvent::new(AuditAction::SignedIn, &user).by_self().with_ip(client_ip(&req));
+            audit::record(audit.get_ref(), event).await;
+            let refresh_token = jwt::issue_refresh_token(tokens.get_ref(), user.id, &user.username).await?;
+            (user.id, user.username, refresh_token)
         }
-        Ok(None) => {
-            HttpResponse::NotFound().body(format!("No user found with username {username}"))
+        TokenRequest::RefreshToken { refresh_token } => {
+            match jwt::rotate_refresh_token(tokens.get_ref(), &refresh_token).await? {
+                Some((refresh_token, user_id, username)) => (user_id, username, refresh_token),
+                None => return Err(AppError::unauthorized("The refresh token is invalid or has expired")),
+            }
+        }
+    };
+
+    let access_token = keys.issue(user_id, &username).map_err(|err| {
+        eprintln!("signing an access token failed: {err}");
+        AppError::internal(err)
+    })?;
+    Ok(HttpResponse::Ok().json(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": jwt::ACCESS_TOKEN_TTL_SECS,
+        "refresh_token": refresh_token,
+    })))
+}
+
+#[derive(Deserialize)]
+struct RevokeRequest {
+    refresh_token: String,
+}
+
+/// Revokes a refresh token together with every token rotated from it.
+#[post("/token/revoke")]
+async fn revoke_token(tokens: web::Data<dyn TokenStore>, body: web::Json<RevokeRequest>) -> AppResult<HttpResponse> {
+    jwt::revoke_refresh_token(tokens.get_ref(), &body.refresh_token).await?;
+    Ok(HttpResponse::Ok().json(json!({"message": "Token revoked"})))
+}
+
+// Loads the record behind `username`, making sure it belongs to the signed in user
+async fn own_record(users: &dyn UserRepository, username: &str, signed_in: &SignedInUser) -> AppResult<UserRecord> {
+    match users.find_by_username(username).await? {
+        // Compared by id, so a session outlives a change of username
+        Some(record) if record.id == signed_in.user_id => Ok(record),
+        Some(_) => Err(not_own_account()),
+        None => Err(no_user(username)),
+    }
+}
+
+fn not_own_account() -> AppError {
+    AppError::forbidden("You can only manage your own account")
+}
+
+// Loads the record behind `username` if the signed in user may do `action` to
+// it: always to their own account, to others only if their role allows it
+async fn authorized_record(
+    users: &dyn UserRepository,
+    username: &str,
+    signed_in: &SignedInUser,
+    action: Action,
+) -> AppResult<UserRecord> {
+    let record = users.find_by_username(username).await?.ok_or_else(|| no_user(username))?;
+    // Compared by id, so a session outlives a change of username
+    if record.id == signed_in.user_id {
+        return Ok(record);
+    }
+    match users.find_by_id(signed_in.user_id).await? {
+        Some(actor) if actor.role.can_act_on(action, record.role) => Ok(record),
+        _ => Err(not_own_account()),
+    }
+}
+
+/// Updates the fields present in the body and returns the updated profile.
+#[patch("/{username}")]
+#[allow(clippy::too_many_arguments)]
+async fn edit_user(
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn AccountTokenStore>,
+    emails: web::Data<AccountEmails>,
+    audit: web::Data<dyn AuditLog>,
+    username: web::Path<String>,
+    changes: Validated<web::Json<UpdateUserRequest>>,
+    signed_in: SignedInUser,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let username = username.into_inner();
+    let mut record = authorized_record(users.get_ref(), &username, &signed_in, Action::EditUser).await?;
+
+    let changes = changes.into_inner().into_inner();
+    // Renaming onto a taken username would be rejected by the unique index
+    if let Some(new_username) = changes.username.as_deref().filter(|&new| new != username) {
+        let holder = users.find_by_username(new_username).await?;
+        // Only changing the case of one's own username is fine
+        if holder.is_some_and(|other| other.id != record.id) {
+            return Err(username_taken(new_username));
         }
-        Err(_) => todo!(),
     }
+    let old_email = record.email.clone();
+    record.apply(changes);
+
+    let updated = users.update(&username, &record).await.map_err(|err| match err {
+        // Someone else took the username between the check and the update
+        RepositoryError::Duplicate("username") => username_taken(&record.username),
+        err => err.into(),
+    })?;
+    if !updated {
+        return Err(no_user(&username));
+    }
+    let event = AuditEvent::new(AuditAction::Edited, &record).by(&signed_in).with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    if record.email != old_email {
+        send_verification(tokens.get_ref(), &emails, &record).await;
+    }
+    Ok(HttpResponse::Ok().json(UserDetails::from(record)))
+}
+
+/// Soft deletes the user with the supplied username and ends all of their
+/// sessions. The account can be restored until it is purged.
+#[delete("/{username}")]
+async fn delete_user(
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionStore>,
+    refresh_tokens: web::Data<dyn TokenStore>,
+    audit: web::Data<dyn AuditLog>,
+    username: web::Path<String>,
+    signed_in: SignedInUser,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let username = username.into_inner();
+    let record = authorized_record(users.get_ref(), &username, &signed_in, Action::DeleteUser).await?;
+    let deleted = users.soft_delete(&username, DateTime::now()).await?;
+    sessions.delete_for_user(record.id).await?;
+    refresh_tokens.delete_for_user(record.id).await?;
+    if !deleted {
+        return Err(no_user(&username));
+    }
+
+    let event = AuditEvent::new(AuditAction::Deleted, &record).by(&signed_in).with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    Ok(HttpResponse::Ok().body("User deleted successfully"))
+}
+
+/// Brings back a deleted user that hasn't been purged yet.
+#[post("/{username}/restore")]
+async fn restore_user(
+    users: web::Data<dyn UserRepository>,
+    audit: web::Data<dyn AuditLog>,
+    username: web::Path<String>,
+    _admin: RequireRole<Admin>,
+    signed_in: SignedInUser,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let username = username.into_inner();
+    let record = users
+        .restore(&username)
+        .await?
+        .ok_or_else(|| AppError::not_found(format!("No deleted user found with username {username}")))?;
+
+    let event = AuditEvent::new(AuditAction::Restored, &record).by(&signed_in).with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    Ok(HttpResponse::Ok().json(UserDetails::from(record)))
+}
+
+/// Gives another user a different role. Admins can't change their own role or
+/// that of another admin.
+#[put("/{username}/role")]
+async fn set_role(
+    users: web::Data<dyn UserRepository>,
+    audit: web::Data<dyn AuditLog>,
+    username: web::Path<String>,
+    body: web::Json<SetRoleRequest>,
+    admin: RequireRole<Admin>,
+    signed_in: SignedInUser,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let username = username.into_inner();
+    let mut record = users.find_by_username(&username).await?.ok_or_else(|| no_user(&username))?;
+    if !admin.user.role.can_act_on(Action::ChangeRole, record.role) {
+        return Err(AppError::forbidden("You can't change the role of this user"));
+    }
+    record.set_role(body.role);
+    // Deleted or renamed since the lookup
+    if !users.update(&username, &record).await? {
+        return Err(no_user(&username));
+    }
+
+    let event = AuditEvent::new(AuditAction::RoleChanged, &record).by(&signed_in).with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    Ok(HttpResponse::Ok().json(UserDetails::from(record)))
+}
+
+fn username_taken(username: &str) -> AppError {
+    AppError::conflict_on("username", format!("Username {username} is already taken"))
 }
 
-//edits User Profile
-#[post("/edit_user/{username}")]
-async fn edit_user(client: web::Data<Client>, username: web::Path<String>, user: web::Form<User>, req: HttpRequest) -> HttpResponse {
+/// Replaces the password after checking the current one.
+///
+/// Every other session and refresh token of the user ends, so a stolen one
+/// doesn't outlive the change. The caller gets a fresh session cookie, or a
+/// fresh refresh token when signed in with an access token.
+#[post("/{username}/password")]
+#[allow(clippy::too_many_arguments)]
+async fn change_password(
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionStore>,
+    refresh_tokens: web::Data<dyn TokenStore>,
+    attempts: web::Data<dyn LoginAttemptStore>,
+    audit: web::Data<dyn AuditLog>,
+    passwords: web::Data<Passwords>,
+    key: web::Data<SessionKey>,
+    config: web::Data<Config>,
+    username: web::Path<String>,
+    body: Validated<web::Json<ChangePasswordRequest>>,
+    signed_in: SignedInUser,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
     let username = username.into_inner();
-    let collection: Collection<User> = client.database(DB_NAME).collection(COLL_NAME);
-    // Create a filter based on the username
-    let filter = doc! { "username": &username };
-
-    // Check if the user is signed in
-    if let Some(cookie) = req.cookie("isSignedIn") {
-        if cookie.value() == "true" {
-            return HttpResponse::Ok().body("You can change credentials");
+    let mut record = own_record(users.get_ref(), &username, &signed_in).await?;
+
+    let body = body.into_inner().into_inner();
+    // Guessing the current password counts towards the sign in lockout
+    let credentials = Credentials {
+        username: record.username.clone(),
+        password: body.current_password,
+    };
+    authenticate(users.get_ref(), attempts.get_ref(), &passwords, &credentials, client_ip(&req)).await?;
+
+    let hash = passwords.hash(&body.new_password).await?;
+    record.set_password_hash(hash);
+    if !users.update(&username, &record).await? {
+        return Err(no_user(&username));
+    }
+    sessions.delete_for_user(record.id).await?;
+    refresh_tokens.delete_for_user(record.id).await?;
+
+    let event = AuditEvent::new(AuditAction::PasswordChanged, &record)
+        .by(&signed_in)
+        .with_ip(client_ip(&req));
+    audit::record(audit.get_ref(), event).await;
+    match signed_in.credential {
+        Credential::Session(_) => {
+            let session = session::start_session(sessions.get_ref(), record.id, &record.username).await?;
+            Ok(HttpResponse::Ok()
+                .cookie(session::session_cookie(&key, &session, config.session.secure_cookie))
+                .json(json!({"message": "Password changed"})))
+        }
+        // The access token itself stays valid until it expires
+        Credential::AccessToken(_) => {
+            let refresh_token = jwt::issue_refresh_token(refresh_tokens.get_ref(), record.id, &record.username).await?;
+            Ok(HttpResponse::Ok().json(json!({"message": "Password changed", "refresh_token": refresh_token})))
         }
-    } else {
-        return HttpResponse::Unauthorized().body("You must be signed in to edit user details");
     }
+}
 
-    if !is_valid_email(&user.email) {
-        return HttpResponse::BadRequest().body("Invalid email format");
+#[post("/sign_out")]
+async fn sign_out(sessions: web::Data<dyn SessionStore>, tokens: web::Data<dyn TokenStore>, signed_in: SignedInUser) -> AppResult<HttpResponse> {
+    // Invalidate the session (or access token) on the server, then clear the cookie
+    match &signed_in.credential {
+        Credential::Session(id) => sessions.delete(id).await?,
+        Credential::AccessToken(claims) => {
+            jwt::revoke_access_token(tokens.get_ref(), &claims.jti, claims.exp).await?
+        }
     }
+    let mut res = HttpResponse::Ok().json(json!({"message": "Sign-out successful"}));
+    res.add_removal_cookie(&session::removal_cookie())
+        .expect("removal cookie is a valid header value");
+    Ok(res)
+}
+
+/// Storage backends shared by every worker.
+#[derive(Clone)]
+struct Stores {
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionStore>,
+    tokens: web::Data<dyn TokenStore>,
+    login_attempts: web::Data<dyn LoginAttemptStore>,
+    account_tokens: web::Data<dyn AccountTokenStore>,
+    audit: web::Data<dyn AuditLog>,
+    health: web::Data<dyn HealthCheck>,
+}
 
-    // Check if passwords match
-    if user.password != user.confirm_password {
-        return HttpResponse::BadRequest().body("Passwords do not match");
+impl Stores {
+    /// Stores backed by MongoDB. The indexes they rely on come from `migrations`.
+    fn mongo(db: &Database, config: &DatabaseConfig) -> Self {
+        let health = MongoHealthCheck::new(db, Duration::from_secs(config.timeout_secs));
+        let users = MongoUserRepository::new(db, &config.users_collection);
+        let sessions = MongoSessionStore::new(db);
+        let tokens = MongoTokenStore::new(db);
+        let login_attempts = MongoLoginAttemptStore::new(db);
+        let account_tokens = MongoAccountTokenStore::new(db);
+        let audit = MongoAuditLog::new(db);
+
+        Stores {
+            users: web::Data::from(Arc::new(users) as Arc<dyn UserRepository>),
+            sessions: web::Data::from(Arc::new(sessions) as Arc<dyn SessionStore>),
+            tokens: web::Data::from(Arc::new(tokens) as Arc<dyn TokenStore>),
+            login_attempts: web::Data::from(Arc::new(login_attempts) as Arc<dyn LoginAttemptStore>),
+            account_tokens: web::Data::from(Arc::new(account_tokens) as Arc<dyn AccountTokenStore>),
+            audit: web::Data::from(Arc::new(audit) as Arc<dyn AuditLog>),
+            health: web::Data::from(Arc::new(health) as Arc<dyn HealthCheck>),
+        }
     }
 
-    // Hash the new password before updating the user
-    let hashed_password = hash_password(&user.password);
-    // Create an update document with the new user data
-    let update_doc = doc! {
-        "$set": {
-            "first_name": &user.first_name,
-            "last_name": &user.last_name,
-            "username": &user.username,
-            "email": &user.email,
-            "password" : &hashed_password
+    /// Stores that keep everything in memory.
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn in_memory() -> Self {
+        Stores {
+            users: web::Data::from(Arc::new(InMemoryUserRepository::new()) as Arc<dyn UserRepository>),
+            sessions: web::Data::from(Arc::new(InMemorySessionStore::new()) as Arc<dyn SessionStore>),
+            tokens: web::Data::from(Arc::new(InMemoryTokenStore::new()) as Arc<dyn TokenStore>),
+            login_attempts: web::Data::from(
+                Arc::new(InMemoryLoginAttemptStore::new()) as Arc<dyn LoginAttemptStore>
+            ),
+            account_tokens: web::Data::from(
+                Arc::new(InMemoryAccountTokenStore::new()) as Arc<dyn AccountTokenStore>
+            ),
+            audit: web::Data::from(Arc::new(InMemoryAuditLog::new()) as Arc<dyn AuditLog>),
+            health: web::Data::from(Arc::new(InMemoryHealthCheck::new()) as Arc<dyn HealthCheck>),
         }
-    };
+    }
+}
+
+/// Registers the shared state and every route of the service.
+fn configure(
+    stores: Stores,
+    config: web::Data<Config>,
+    emails: web::Data<AccountEmails>,
+    passwords: web::Data<Passwords>,
+    session_key: web::Data<SessionKey>,
+    jwt_keys: web::Data<JwtKeys>,
+) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(stores.users)
+            .app_data(stores.sessions)
+            .app_data(stores.tokens)
+            .app_data(stores.login_attempts)
+            .app_data(stores.account_tokens)
+            .app_data(stores.audit)
+            .app_data(stores.health)
+            .app_data(config)
+            .app_data(emails)
+            .app_data(passwords)
+            .app_data(session_key)
+            .app_data(jwt_keys)
+            .service(healthz)
+            .service(readyz)
+            .service(add_user)
+            .service(get_user)
+            .service(verify_email)
+            .service(resend_verification)
+            .service(request_password_reset)
+            .service(confirm_password_reset)
+            .service(sign_in)
+            .service(sign_out)
+            .service(issue_token)
+            .service(revoke_token)
+            // Account management is only reachable with a valid session or bearer token
+            .service(
+                web::scope("/users")
+                    .wrap(IsUserSignedInMiddleware::new())
+                    .service(list_users)
+                    .service(edit_user)
+                    .service(delete_user)
+                    .service(restore_user)
+                    .service(set_role)
+                    .service(change_password),
+            );
+    }
+}
 
-    // Use the update_one method to update the user
-    match collection.update_one(filter, update_doc, None).await {
-        Ok(result) => {
-            if result.modified_count > 0 {
-                HttpResponse::Ok().body("User updated successfully")
-            } else {
-                HttpResponse::NotFound().body(format!("No user found with username {}", username))
+// Limits the endpoints that guess passwords, create accounts or send emails.
+// The store is shared by all workers, so the limits hold per instance.
+fn rate_limit(config: &RateLimitConfig, store: Arc<InMemoryStore>) -> Hooks<RateLimit<ClientIp, InMemoryStore>> {
+    let client = if config.trust_proxy { ClientIp::forwarded() } else { ClientIp::peer() };
+    let sign_ins = Quota::per_minute(config.sign_in_per_minute);
+    let emails = Quota::per_hour(config.emails_per_hour);
+    Hooks::new(
+        RateLimit::new(client, store)
+            .route("/sign_in", sign_ins)
+            .route("/token", sign_ins)
+            .route("/add_user", Quota::per_hour(config.sign_up_per_hour))
+            .route("/verify_email/resend", emails)
+            .route("/password_reset", emails),
+    )
+}
+
+// Lets the configured frontends call every endpoint; with no origins
+// configured, browsers keep to same-origin requests
+fn cors(config: &CorsConfig) -> Hooks<Cors> {
+    use actix_web::http::Method;
+    use middleware::rate_limit::{RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET};
+
+    let mut cors = Cors::new()
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .expose_headers([header::RETRY_AFTER, RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET])
+        .max_age(Duration::from_secs(config.max_age_secs));
+    for origin in &config.allowed_origins {
+        cors = match origin.strip_prefix("regex:") {
+            Some(pattern) => cors.allow_origin_regex(regex::Regex::new(pattern).expect("cors origins are validated")),
+            None if origin == "*" => cors.allow_any_origin(),
+            None => cors.allow_origin(origin),
+        };
+    }
+    if config.allow_credentials {
+        cors = cors.allow_credentials();
+    }
+    Hooks::new(cors)
+}
+
+// Connects to MongoDB, retrying with exponential backoff while it is
+// unreachable, e.g. when both are starting at the same time
+async fn connect(config: &DatabaseConfig) -> RepositoryResult<Database> {
+    let uri = config.uri.as_deref().expect("database.uri is validated");
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let mut options = ClientOptions::parse(uri).await?;
+    options.server_selection_timeout = Some(timeout);
+    options.connect_timeout = Some(timeout);
+    let db = Client::with_options(options)?.database(&config.name);
+    let health = MongoHealthCheck::new(&db, timeout);
+
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match health.check().await {
+            Ok(()) => return Ok(db),
+            Err(RepositoryError::Unavailable(err)) if attempt < config.connect_attempts => {
+                eprintln!(
+                    "connecting to MongoDB failed (attempt {attempt} of {}), retrying in {delay:?}: {err}",
+                    config.connect_attempts
+                );
+                actix_web::rt::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(30));
+                attempt += 1;
             }
+            Err(err) => return Err(err),
         }
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
 }
 
-/// Creates an index on the "username" field to force the values to be unique.
-async fn create_username_index(client: &Client) {
-    let options = IndexOptions::builder().unique(true).build();
-    let model = IndexModel::builder()
-        .keys(doc! { "username": 1 })
-        .options(options)
-        .build();
-    client
-        .database(DB_NAME)
-        .collection::<User>(COLL_NAME)
-        .create_index(model, None)
-        .await
-        .expect("creating an index should succeed");
+// Hard deletes the users soft deleted more than `retention_days` ago
+async fn purge_expired_users(users: &dyn UserRepository, audit: &dyn AuditLog, retention_days: i64) {
+    let before = DateTime::now().timestamp_millis() - retention_days * 24 * 60 * 60 * 1000;
+    match users.purge_deleted(DateTime::from_millis(before)).await {
+        Ok(purged) => {
+            for user in &purged {
+                audit::record(audit, AuditEvent::new(AuditAction::Purged, user)).await;
+            }
+        }
+        Err(err) => eprintln!("purging deleted users failed: {err}"),
+    }
 }
 
-#[get("/sign_out")]
-async fn sign_out() -> HttpResponse {
-    // Clear the user cookie to sign out
-    HttpResponse::Ok()
-        .cookie(
-            actix_web::cookie::Cookie::named("isloggedIn")
-        ) 
-        .json(json!({"message": "Sign-out successful"}))
-
+// Runs `purge_expired_users` once an hour for as long as the server runs
+fn spawn_purge_task(users: web::Data<dyn UserRepository>, audit: web::Data<dyn AuditLog>, retention_days: i64) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            purge_expired_users(users.get_ref(), audit.get_ref(), retention_days).await;
+        }
+    });
 }
 
+// Makes the user named by `INITIAL_ADMIN` an admin, so there is someone to
+// hand out roles on a fresh database
+async fn promote_initial_admin(users: &dyn UserRepository, username: &str) {
+    match users.find_by_username(username).await {
+        Ok(Some(mut record)) if record.role != Role::Admin => {
+            record.set_role(Role::Admin);
+            users
+                .update(username, &record)
+                .await
+                .expect("promoting the initial admin should succeed");
+            println!("{username} is now an admin");
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => eprintln!("INITIAL_ADMIN {username} does not exist yet; sign up and restart"),
+        Err(err) => panic!("looking up INITIAL_ADMIN failed: {err}"),
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let uri = std::env::var("MONGODB_URI").expect("MONGODB_URI must be set in the .env file");
+    let cli = Cli::parse();
+    let migrate_only = cli.migrate_only;
+    let config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
 
-    let client = Client::with_uri_str(uri).await.expect("failed to connect");
-    create_username_index(&client).await;
+    let db = match connect(&config.database).await {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("connecting to MongoDB failed: {err}");
+            std::process::exit(1);
+        }
+    };
+    match migrations::run(&db, &config.database.users_collection).await {
+        Ok(applied) => applied.iter().for_each(|name| println!("applied migration {name}")),
+        Err(err) => {
+            eprintln!("migrating the database failed: {err}");
+            std::process::exit(1);
+        }
+    }
+    if migrate_only {
+        return Ok(());
+    }
+
+    let emails = web::Data::new(AccountEmails::from_config(&config.mail).expect("failed to set up the mailer"));
+    let passwords = web::Data::new(
+        Passwords::new(HashAlgorithm::from_config(&config.password)).expect("password settings are validated"),
+    );
+    let session_key = web::Data::new(SessionKey::from_config(&config.session));
+    let jwt_keys = web::Data::new(JwtKeys::from_config(&config.jwt).expect("failed to load the JWT keys"));
 
-    HttpServer::new(move || {
+    let stores = Stores::mongo(&db, &config.database);
+    if let Some(username) = &config.accounts.initial_admin {
+        promote_initial_admin(stores.users.get_ref(), username).await;
+    }
+    spawn_purge_task(stores.users.clone(), stores.audit.clone(), config.accounts.retention_days.into());
+
+    let bind = config.server.bind.clone();
+    let workers = config.server.workers;
+    let config = web::Data::new(config);
+    let rate_limits = Arc::new(InMemoryStore::new());
+    let mut server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(client.clone()))
-            .service(add_user)
-            .service(get_user)
-            .service(sign_in_user)
-            .service(sign_out)
-            // .wrap_fn(is_user_signed_in) // Apply the middleware to protect routes
-            // .service(web::resource("/protected").route(web::get().to(delete_user)))
-            // .service(web::resource("/protected").route(web::get().to(edit_user)))
-            // .service(web::resource("/protected").route(web::get().to(sign_out)))
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
-}
\ No newline at end of file
+            .wrap(rate_limit(&config.rate_limit, rate_limits.clone()))
+            // Outside the limits, so a 429 is readable by the frontend too
+            .wrap(cors(&config.cors))
+            .configure(configure(
+                stores.clone(),
+                config.clone(),
+                emails.clone(),
+                passwords.clone(),
+                session_key.clone(),
+                jwt_keys.clone(),
+            ))
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    server.bind(bind)?.run().await
+}
+
+#[cfg(test)]
+mod tests;
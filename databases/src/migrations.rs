@@ -0,0 +1,437 @@
+//! Versioned changes to the MongoDB schema.
+//!
+//! `MIGRATIONS` are applied in order, and each is recorded in the
+//! "_migrations" collection so it runs exactly once per database. A lease in
+//! "_migrations_lock" lets only one instance migrate at a time; instances
+//! starting alongside it wait, then find nothing left to do. The holder renews
+//! the lease while it migrates, and stops if it ever loses it.
+//!
+//! A released migration is never edited. Changing the schema means appending
+//! a new one. A migration that fails halfway runs again on the next start, so
+//! each must be safe to repeat.
+use std::collections::HashSet;
+use std::pin::pin;
+use std::time::Duration;
+
+use derive_more::Display;
+use futures_util::future::{self, BoxFuture, Either};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions};
+use mongodb::{Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+
+use crate::account::ACCOUNT_TOKENS_COLL_NAME;
+use crate::audit::USER_AUDIT_COLL_NAME;
+use crate::jwt::{random_string, REFRESH_TOKENS_COLL_NAME, REVOKED_TOKENS_COLL_NAME};
+use crate::lockout::{ATTEMPTS_RESET_SECS, LOGIN_ATTEMPTS_COLL_NAME};
+use crate::repository::{ignoring_case, is_duplicate_key, RepositoryError};
+use crate::session::SESSIONS_COLL_NAME;
+
+const MIGRATIONS_COLL_NAME: &str = "_migrations";
+const LOCK_COLL_NAME: &str = "_migrations_lock";
+const LOCK_ID: &str = "lock";
+// A lock left behind by a crashed instance is taken over after this long
+const LOCK_LEASE_SECS: i64 = 5 * 60;
+// Often enough that a few failed renewals still leave time on the lease
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Server error code for dropping an index that doesn't exist
+const INDEX_NOT_FOUND: i32 = 27;
+
+/// The collections a migration works on.
+pub struct Schema {
+    db: Database,
+    users: Collection<Document>,
+}
+
+impl Schema {
+    fn collection(&self, name: &str) -> Collection<Document> {
+        self.db.collection(name)
+    }
+}
+
+type Up = for<'a> fn(&'a Schema) -> BoxFuture<'a, mongodb::error::Result<()>>;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    up: Up,
+}
+
+/// Every migration, in the order they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users_unique_username",
+        up: users_unique_username,
+    },
+    Migration {
+        version: 2,
+        name: "users_listing_indexes",
+        up: users_listing_indexes,
+    },
+    Migration {
+        version: 3,
+        name: "sessions_indexes",
+        up: sessions_indexes,
+    },
+    Migration {
+        version: 4,
+        name: "refresh_tokens_indexes",
+        up: refresh_tokens_indexes,
+    },
+    Migration {
+        version: 5,
+        name: "login_attempts_ttl",
+        up: login_attempts_ttl,
+    },
+    Migration {
+        version: 6,
+        name: "account_tokens_indexes",
+        up: account_tokens_indexes,
+    },
+    Migration {
+        version: 7,
+        name: "user_audit_index",
+        up: user_audit_index,
+    },
+    Migration {
+        version: 8,
+        name: "users_backfill_fields",
+        up: users_backfill_fields,
+    },
+    Migration {
+        version: 9,
+        name: "users_case_insensitive_unique_username_and_email",
+        up: users_case_insensitive_unique_username_and_email,
+    },
+];
+
+// Removes documents once the time in `field` has passed
+fn expires_at(field: &str, after: Duration) -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { field: 1 })
+        .options(IndexOptions::builder().expire_after(after).build())
+        .build()
+}
+
+fn index(keys: Document) -> IndexModel {
+    IndexModel::builder().keys(keys).build()
+}
+
+fn users_unique_username(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let model = IndexModel::builder()
+            .keys(doc! { "username": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        schema.users.create_index(model, None).await?;
+        Ok(())
+    })
+}
+
+// The sort orders and prefix search of the user listing, the email lookup,
+// and finding the deleted users to purge
+fn users_listing_indexes(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let models = vec![
+            index(doc! { "created_at": 1, "_id": 1 }),
+            index(doc! { "username": 1, "_id": 1 }),
+            index(doc! { "email": 1 }),
+            // Only deleted users have the field, so the index stays small
+            IndexModel::builder()
+                .keys(doc! { "deleted_at": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+        ];
+        schema.users.create_indexes(models, None).await?;
+        Ok(())
+    })
+}
+
+fn sessions_indexes(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let models = vec![expires_at("expires_at", Duration::ZERO), index(doc! { "user_id": 1 })];
+        schema.collection(SESSIONS_COLL_NAME).create_indexes(models, None).await?;
+        Ok(())
+    })
+}
+
+fn refresh_tokens_indexes(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let models = vec![
+            expires_at("expires_at", Duration::ZERO),
+            index(doc! { "family": 1 }),
+            index(doc! { "user_id": 1 }),
+        ];
+        schema.collection(REFRESH_TOKENS_COLL_NAME).create_indexes(models, None).await?;
+        schema
+            .collection(REVOKED_TOKENS_COLL_NAME)
+            .create_index(expires_at("expires_at", Duration::ZERO), None)
+            .await?;
+        Ok(())
+    })
+}
+
+fn login_attempts_ttl(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let model = expires_at("last_failure", Duration::from_secs(ATTEMPTS_RESET_SECS));
+        schema.collection(LOGIN_ATTEMPTS_COLL_NAME).create_index(model, None).await?;
+        Ok(())
+    })
+}
+
+fn account_tokens_indexes(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let models = vec![
+            expires_at("expires_at", Duration::ZERO),
+            index(doc! { "user_id": 1, "purpose": 1 }),
+        ];
+        schema.collection(ACCOUNT_TOKENS_COLL_NAME).create_indexes(models, None).await?;
+        Ok(())
+    })
+}
+
+fn user_audit_index(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let model = index(doc! { "user_id": 1, "at": 1 });
+        schema.collection(USER_AUDIT_COLL_NAME).create_index(model, None).await?;
+        Ok(())
+    })
+}
+
+// Users created before email verification and roles existed are verified,
+// plain users, created when their id was. Stored explicitly, so queries and
+// the listing indexes on the fields find them.
+fn users_backfill_fields(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        schema
+            .users
+            .update_many(
+                doc! { "email_verified": { "$exists": false } },
+                doc! { "$set": { "email_verified": true } },
+                None,
+            )
+            .await?;
+        schema
+            .users
+            .update_many(
+                doc! { "role": { "$exists": false } },
+                doc! { "$set": { "role": "user" } },
+                None,
+            )
+            .await?;
+        // Pipelines, to read the fields being set from
+        schema
+            .users
+            .update_many(
+                doc! { "created_at": { "$exists": false } },
+                vec![doc! { "$set": { "created_at": { "$toDate": "$_id" } } }],
+                None,
+            )
+            .await?;
+        schema
+            .users
+            .update_many(
+                doc! { "updated_at": { "$exists": false } },
+                vec![doc! { "$set": { "updated_at": "$created_at" } }],
+                None,
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+// Replaces the unique username index with one that ignores case, and makes
+// emails unique the same way. Fails while existing users collide; those have
+// to be resolved by hand first.
+fn users_case_insensitive_unique_username_and_email(schema: &Schema) -> BoxFuture<'_, mongodb::error::Result<()>> {
+    Box::pin(async move {
+        let unique = |field: &str| {
+            let options = IndexOptions::builder()
+                .name(format!("{field}_unique"))
+                .unique(true)
+                .collation(ignoring_case())
+                .build();
+            IndexModel::builder().keys(doc! { field: 1 }).options(options).build()
+        };
+        schema
+            .users
+            .create_indexes(vec![unique("username"), unique("email")], None)
+            .await?;
+        // Gone already if the migration ran halfway before
+        match schema.users.drop_index("username_1", None).await {
+            Err(err) if !matches!(&*err.kind, ErrorKind::Command(err) if err.code == INDEX_NOT_FOUND) => Err(err),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// A migration as recorded in the "_migrations" collection.
+#[derive(Debug, Deserialize, Serialize)]
+struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: i32,
+    name: String,
+    applied_at: DateTime,
+}
+
+/// Errors that stop the migrations.
+#[derive(Debug, Display)]
+pub enum MigrationError {
+    #[display(fmt = "{}", _0)]
+    Repository(RepositoryError),
+    /// The lease ran out and another instance may be migrating now
+    #[display(fmt = "lost the migration lock to another instance")]
+    LockLost,
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<RepositoryError> for MigrationError {
+    fn from(err: RepositoryError) -> Self {
+        MigrationError::Repository(err)
+    }
+}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(err: mongodb::error::Error) -> Self {
+        MigrationError::Repository(err.into())
+    }
+}
+
+pub type MigrationResult<T> = Result<T, MigrationError>;
+
+/// Applies the migrations this database hasn't seen yet and returns their names.
+pub async fn run(db: &Database, users_collection: &str) -> MigrationResult<Vec<&'static str>> {
+    let lock = Lock::acquire(db).await?;
+    // The heartbeat only finishes when the lease is lost, cancelling the migrations
+    let result = {
+        let migrating = pin!(apply_pending(db, users_collection, &lock));
+        match future::select(migrating, pin!(lock.heartbeat())).await {
+            Either::Left((result, _)) => result,
+            Either::Right((err, _)) => Err(err),
+        }
+    };
+    lock.release().await;
+    result
+}
+
+async fn apply_pending(db: &Database, users_collection: &str, lock: &Lock) -> MigrationResult<Vec<&'static str>> {
+    let records: Collection<AppliedMigration> = db.collection(MIGRATIONS_COLL_NAME);
+    let mut applied = HashSet::new();
+    let mut cursor = records.find(None, None).await?;
+    while cursor.advance().await? {
+        applied.insert(cursor.deserialize_current()?.version);
+    }
+    let newest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if let Some(unknown) = applied.iter().filter(|&&version| version > newest).max() {
+        eprintln!("the database has migration {unknown}, which this build doesn't know; is it outdated?");
+    }
+
+    let schema = Schema {
+        db: db.clone(),
+        users: db.collection(users_collection),
+    };
+    let mut names = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| !applied.contains(&migration.version)) {
+        lock.check().await?;
+        (migration.up)(&schema).await?;
+        // Recorded only by the holder, or two instances would both insert it
+        lock.check().await?;
+        records
+            .insert_one(
+                AppliedMigration {
+                    version: migration.version,
+                    name: migration.name.to_owned(),
+                    applied_at: DateTime::now(),
+                },
+                None,
+            )
+            .await?;
+        names.push(migration.name);
+    }
+    Ok(names)
+}
+
+// Lease on the right to migrate, held by one instance at a time
+struct Lock {
+    collection: Collection<Document>,
+    owner: String,
+}
+
+impl Lock {
+    // Waits until no other instance holds the lock, or its lease ran out
+    async fn acquire(db: &Database) -> MigrationResult<Self> {
+        let collection = db.collection::<Document>(LOCK_COLL_NAME);
+        let owner = random_string(16);
+        let mut waiting = false;
+        loop {
+            let now = DateTime::now();
+            let lease_end = lease_end(now);
+            // Without a lock document, or with an expired one, this takes the
+            // lock; otherwise the upsert collides with the holder's document
+            let taken = collection
+                .find_one_and_update(
+                    doc! { "_id": LOCK_ID, "expires_at": { "$lte": now } },
+                    doc! { "$set": { "owner": &owner, "expires_at": lease_end } },
+                    FindOneAndUpdateOptions::builder().upsert(true).build(),
+                )
+                .await;
+            match taken {
+                Ok(_) => return Ok(Lock { collection, owner }),
+                Err(err) if is_duplicate_key(&err) => {
+                    if !waiting {
+                        println!("waiting for another instance to finish migrating the database");
+                        waiting = true;
+                    }
+                    actix_web::rt::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    // Extends the lease, as long as it is still ours
+    async fn renew(&self) -> MigrationResult<bool> {
+        let held = doc! { "_id": LOCK_ID, "owner": &self.owner };
+        let renewed = doc! { "$set": { "expires_at": lease_end(DateTime::now()) } };
+        let result = self.collection.update_one(held, renewed, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    // Fails once another instance may have taken the lock over
+    async fn check(&self) -> MigrationResult<()> {
+        if self.renew().await? {
+            Ok(())
+        } else {
+            Err(MigrationError::LockLost)
+        }
+    }
+
+    // Keeps the lease from running out; only returns when it can't
+    async fn heartbeat(&self) -> MigrationError {
+        loop {
+            actix_web::rt::time::sleep(LOCK_RENEW_INTERVAL).await;
+            match self.renew().await {
+                Ok(true) => {}
+                Ok(false) => return MigrationError::LockLost,
+                // The next beat may get through while the lease lasts
+                Err(err) => eprintln!("renewing the migration lock failed: {err}"),
+            }
+        }
+    }
+
+    // A lock that can't be released is taken over once its lease ends
+    async fn release(self) {
+        let held = doc! { "_id": LOCK_ID, "owner": &self.owner };
+        if let Err(err) = self.collection.delete_one(held, None).await {
+            eprintln!("releasing the migration lock failed: {err}");
+        }
+    }
+}
+
+fn lease_end(now: DateTime) -> DateTime {
+    DateTime::from_millis(now.timestamp_millis() + LOCK_LEASE_SECS * 1000)
+}
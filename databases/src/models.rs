@@ -0,0 +1,337 @@
+//! The shapes a user takes on its way through the service.
+//!
+//! Requests come in as `CreateUserRequest`/`UpdateUserRequest`, are persisted
+//! as `UserRecord` and go back out as `UserDetails` to the user and those
+//! managing it, or as `PublicProfile` to anyone else. Only `UserRecord` carries
+//! the password hash, and it is never serialized into a response.
+use mongodb::bson::{oid::ObjectId, serde_helpers, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::roles::Role;
+use crate::validation::{normalize_email, Validate, Validator};
+
+// Limits shared by the create and update rules
+const MAX_NAME_LEN: usize = 64;
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Form body of `add_user`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreateUserRequest {
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+impl Validate for CreateUserRequest {
+    fn normalize(&mut self) {
+        trim(&mut self.first_name);
+        trim(&mut self.last_name);
+        trim(&mut self.username);
+        normalize_email(&mut self.email);
+    }
+
+    fn validate(&self, v: &mut Validator) {
+        v.field("first_name", &self.first_name).length(1, MAX_NAME_LEN);
+        v.field("last_name", &self.last_name).length(1, MAX_NAME_LEN);
+        v.field("username", &self.username)
+            .length(MIN_USERNAME_LEN, MAX_USERNAME_LEN)
+            .username_charset();
+        v.field("email", &self.email).email();
+        v.field("password", &self.password).password_strength();
+        v.field("confirm_password", &self.confirm_password)
+            .matches(&self.password, "Passwords do not match");
+    }
+}
+
+/// Body of `edit_user`; only the fields present are changed.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUserRequest {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+impl Validate for UpdateUserRequest {
+    fn normalize(&mut self) {
+        self.first_name.iter_mut().for_each(trim);
+        self.last_name.iter_mut().for_each(trim);
+        self.username.iter_mut().for_each(trim);
+        self.email.iter_mut().for_each(normalize_email);
+    }
+
+    fn validate(&self, v: &mut Validator) {
+        if let Some(first_name) = &self.first_name {
+            v.field("first_name", first_name).length(1, MAX_NAME_LEN);
+        }
+        if let Some(last_name) = &self.last_name {
+            v.field("last_name", last_name).length(1, MAX_NAME_LEN);
+        }
+        if let Some(username) = &self.username {
+            v.field("username", username)
+                .length(MIN_USERNAME_LEN, MAX_USERNAME_LEN)
+                .username_charset();
+        }
+        if let Some(email) = &self.email {
+            v.field("email", email).email();
+        }
+    }
+}
+
+/// Body of `change_password`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    pub confirm_password: String,
+}
+
+impl Validate for ChangePasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.field("new_password", &self.new_password).password_strength();
+        v.field("confirm_password", &self.confirm_password)
+            .matches(&self.new_password, "Passwords do not match");
+    }
+}
+
+/// Body of `verify_email`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+/// Body of the endpoints that mail a token to an address.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailRequest {
+    pub email: String,
+}
+
+impl Validate for EmailRequest {
+    fn normalize(&mut self) {
+        normalize_email(&mut self.email);
+    }
+
+    fn validate(&self, v: &mut Validator) {
+        v.field("email", &self.email).email();
+    }
+}
+
+/// Body of `confirm_password_reset`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+    pub confirm_password: String,
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.field("new_password", &self.new_password).password_strength();
+        v.field("confirm_password", &self.confirm_password)
+            .matches(&self.new_password, "Passwords do not match");
+    }
+}
+
+/// Body of `set_role`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
+}
+
+fn trim(value: &mut String) {
+    let trimmed = value.trim();
+    if trimmed.len() != value.len() {
+        *value = trimmed.to_owned();
+    }
+}
+
+/// A user as stored in the "users" collection.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "StoredUser")]
+pub struct UserRecord {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub role: Role,
+    // Stored as "password" so documents written before the split still load
+    #[serde(rename = "password")]
+    pub password_hash: String,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+    /// Set while the user is soft deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+}
+
+fn verified_by_default() -> bool {
+    true
+}
+
+// `UserRecord` as read back, filling in what documents written by earlier
+// versions lack
+#[derive(Deserialize)]
+struct StoredUser {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    first_name: String,
+    last_name: String,
+    username: String,
+    email: String,
+    // Accounts created before verification existed count as verified
+    #[serde(default = "verified_by_default")]
+    email_verified: bool,
+    #[serde(default)]
+    role: Role,
+    #[serde(rename = "password")]
+    password_hash: String,
+    // Missing before the timestamps were added
+    created_at: Option<DateTime>,
+    updated_at: Option<DateTime>,
+    #[serde(default)]
+    deleted_at: Option<DateTime>,
+}
+
+impl From<StoredUser> for UserRecord {
+    fn from(stored: StoredUser) -> Self {
+        // The id holds when the document was inserted
+        let created_at = stored.created_at.unwrap_or_else(|| stored.id.timestamp());
+        UserRecord {
+            id: stored.id,
+            first_name: stored.first_name,
+            last_name: stored.last_name,
+            username: stored.username,
+            email: stored.email,
+            email_verified: stored.email_verified,
+            role: stored.role,
+            password_hash: stored.password_hash,
+            created_at,
+            updated_at: stored.updated_at.unwrap_or(created_at),
+            deleted_at: stored.deleted_at,
+        }
+    }
+}
+
+impl UserRecord {
+    /// Builds the record for a new user; `password_hash` must already be hashed.
+    pub fn new(request: CreateUserRequest, password_hash: String) -> Self {
+        let now = DateTime::now();
+        UserRecord {
+            id: ObjectId::new(),
+            first_name: request.first_name,
+            last_name: request.last_name,
+            username: request.username,
+            email: request.email,
+            email_verified: false,
+            role: Role::User,
+            password_hash,
+            created_at: now,
+            updated_at: now,
+            deleted_at: None,
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Applies the fields present in a partial update.
+    pub fn apply(&mut self, request: UpdateUserRequest) {
+        if let Some(first_name) = request.first_name {
+            self.first_name = first_name;
+        }
+        if let Some(last_name) = request.last_name {
+            self.last_name = last_name;
+        }
+        if let Some(username) = request.username {
+            self.username = username;
+        }
+        if let Some(email) = request.email.filter(|email| *email != self.email) {
+            // A new address has to be verified again
+            self.email = email;
+            self.email_verified = false;
+        }
+        self.updated_at = DateTime::now();
+    }
+
+    pub fn verify_email(&mut self) {
+        self.email_verified = true;
+        self.updated_at = DateTime::now();
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+        self.updated_at = DateTime::now();
+    }
+
+    /// Replaces the password; `password_hash` must already be hashed.
+    pub fn set_password_hash(&mut self, password_hash: String) {
+        self.password_hash = password_hash;
+        self.updated_at = DateTime::now();
+    }
+}
+
+/// What anyone, signed in or not, gets to see of a user; no contact details.
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicProfile {
+    #[serde(serialize_with = "serde_helpers::serialize_object_id_as_hex_string")]
+    pub id: ObjectId,
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    #[serde(with = "serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub created_at: DateTime,
+}
+
+impl From<UserRecord> for PublicProfile {
+    fn from(record: UserRecord) -> Self {
+        PublicProfile {
+            id: record.id,
+            first_name: record.first_name,
+            last_name: record.last_name,
+            username: record.username,
+            created_at: record.created_at,
+        }
+    }
+}
+
+/// The whole account, for the user and the moderators and admins managing it.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserDetails {
+    #[serde(serialize_with = "serde_helpers::serialize_object_id_as_hex_string")]
+    pub id: ObjectId,
+    pub first_name: String,
+    pub last_name: String,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub role: Role,
+    #[serde(with = "serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub created_at: DateTime,
+    #[serde(with = "serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub updated_at: DateTime,
+}
+
+impl From<UserRecord> for UserDetails {
+    fn from(record: UserRecord) -> Self {
+        UserDetails {
+            id: record.id,
+            first_name: record.first_name,
+            last_name: record.last_name,
+            username: record.username,
+            email: record.email,
+            email_verified: record.email_verified,
+            role: record.role,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        }
+    }
+}
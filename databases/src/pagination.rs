@@ -0,0 +1,120 @@
+//! Query parameters and cursors of the user listing.
+//!
+//! A cursor is the sort key and id of the last user of a page, base64 encoded
+//! so clients treat it as opaque. Resuming after that position instead of
+//! skipping an offset keeps pages stable while users are added or removed.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{UserDetails, UserRecord};
+use crate::repository::{SortField, SortKey, SortOrder, UserQuery};
+use crate::validation::{ValidationErrors, Validator};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+// Larger requested pages are cut down to this
+const MAX_PAGE_SIZE: usize = 100;
+const MAX_SEARCH_LEN: usize = 64;
+
+/// Query string of `list_users`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ListUsersParams {
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub sort: SortField,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Prefix of the username or email
+    pub q: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Cursor {
+    order: SortOrder,
+    after: SortKey,
+    id: ObjectId,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("a cursor always serializes");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+impl ListUsersParams {
+    /// Checks the parameters and turns them into a repository query.
+    ///
+    /// The query asks for one user more than the page size, which tells
+    /// `Page::new` whether there is a next page.
+    pub fn into_query(self) -> Result<UserQuery, ValidationErrors> {
+        let mut v = Validator::default();
+        let prefix = self.q.map(|q| q.trim().to_owned()).filter(|q| !q.is_empty());
+        if let Some(prefix) = &prefix {
+            v.field("q", prefix).length(1, MAX_SEARCH_LEN);
+        }
+        let after = match self.cursor.as_deref().map(Cursor::decode) {
+            None => None,
+            Some(Some(cursor)) if cursor.after.field() == self.sort && cursor.order == self.order => {
+                Some((cursor.after, cursor.id))
+            }
+            Some(Some(_)) => {
+                v.error("cursor", "mismatch", "belongs to a listing with a different sort order");
+                None
+            }
+            Some(None) => {
+                v.error("cursor", "invalid_cursor", "is not a cursor returned by this endpoint");
+                None
+            }
+        };
+        v.finish()?;
+
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        Ok(UserQuery {
+            prefix,
+            sort: self.sort,
+            order: self.order,
+            after,
+            limit: limit + 1,
+        })
+    }
+}
+
+/// One page of users and the cursor of the next one.
+#[derive(Serialize)]
+pub struct Page {
+    pub items: Vec<UserDetails>,
+    pub next_cursor: Option<String>,
+}
+
+impl Page {
+    /// Builds the page from the users `query` returned.
+    pub fn new(query: &UserQuery, mut users: Vec<UserRecord>) -> Self {
+        let page_size = query.limit - 1;
+        let next_cursor = if users.len() > page_size {
+            users.truncate(page_size);
+            users.last().map(|last| {
+                Cursor {
+                    order: query.order,
+                    after: SortKey::of(query.sort, last),
+                    id: last.id,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        Page {
+            items: users.into_iter().map(UserDetails::from).collect(),
+            next_cursor,
+        }
+    }
+}
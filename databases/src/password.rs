@@ -0,0 +1,153 @@
+//! Password hashing with bcrypt or Argon2id.
+//!
+//! Hashing is slow on purpose, so it never runs on an actix worker thread:
+//! every hash and check goes through `web::block`, which hands it to actix's
+//! bounded pool of blocking threads. Stored hashes that don't match the
+//! configured algorithm and cost are reported by `verify`, so they can be
+//! replaced the next time the user signs in.
+use actix_web::web;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params, Version};
+use derive_more::Display;
+use errors::AppError;
+
+use crate::config::{PasswordAlgorithm, PasswordConfig};
+
+/// The algorithm new hashes are made with, and its cost.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Bcrypt { cost: u32 },
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+
+impl HashAlgorithm {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        match config.algorithm {
+            PasswordAlgorithm::Argon2id => HashAlgorithm::Argon2id {
+                memory_kib: config.argon2_memory_kib,
+                iterations: config.argon2_iterations,
+                parallelism: config.argon2_parallelism,
+            },
+            PasswordAlgorithm::Bcrypt => HashAlgorithm::Bcrypt {
+                cost: config.bcrypt_cost,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum PasswordError {
+    /// The stored hash isn't one this service can read
+    #[display(fmt = "malformed password hash")]
+    MalformedHash,
+    #[display(fmt = "hashing failed: {}", _0)]
+    Hashing(String),
+    /// The blocking pool shut down before the work ran
+    #[display(fmt = "the blocking thread pool is unavailable")]
+    Canceled,
+}
+
+impl std::error::Error for PasswordError {}
+
+impl From<PasswordError> for AppError {
+    fn from(err: PasswordError) -> Self {
+        eprintln!("password hashing failed: {err}");
+        AppError::internal(err)
+    }
+}
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    Mismatch,
+    Match,
+    /// Right password, but the hash should be replaced with a current one
+    MatchOutdated,
+}
+
+/// Hashes and checks passwords with the configured algorithm.
+pub struct Passwords {
+    algorithm: HashAlgorithm,
+    // Checked against when the username is unknown, so that both cases take as long
+    dummy_hash: String,
+}
+
+impl Passwords {
+    pub fn new(algorithm: HashAlgorithm) -> Result<Self, PasswordError> {
+        let dummy_hash = hash_with(&algorithm, "not a real password")?;
+        Ok(Passwords { algorithm, dummy_hash })
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let algorithm = self.algorithm.clone();
+        let password = password.to_owned();
+        web::block(move || hash_with(&algorithm, &password))
+            .await
+            .map_err(|_| PasswordError::Canceled)?
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<Verification, PasswordError> {
+        let algorithm = self.algorithm.clone();
+        let password = password.to_owned();
+        let hash = hash.to_owned();
+        web::block(move || verify_with(&algorithm, &password, &hash))
+            .await
+            .map_err(|_| PasswordError::Canceled)?
+    }
+
+    /// Takes as long as checking a real password, without a user to check it for.
+    pub async fn verify_dummy(&self, password: &str) -> Result<(), PasswordError> {
+        self.verify(password, &self.dummy_hash).await.map(|_| ())
+    }
+}
+
+fn argon2(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Argon2<'static>, PasswordError> {
+    let params = Params::new(memory_kib, iterations, parallelism, None)
+        .map_err(|err| PasswordError::Hashing(err.to_string()))?;
+    Ok(Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params))
+}
+
+fn hash_with(algorithm: &HashAlgorithm, password: &str) -> Result<String, PasswordError> {
+    match *algorithm {
+        HashAlgorithm::Bcrypt { cost } => {
+            bcrypt::hash(password, cost).map_err(|err| PasswordError::Hashing(err.to_string()))
+        }
+        HashAlgorithm::Argon2id { memory_kib, iterations, parallelism } => {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2(memory_kib, iterations, parallelism)?
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|err| PasswordError::Hashing(err.to_string()))
+        }
+    }
+}
+
+fn verify_with(algorithm: &HashAlgorithm, password: &str, hash: &str) -> Result<Verification, PasswordError> {
+    // Which algorithm made the hash decides how to check it, whatever new hashes use
+    let (matches, current) = if hash.starts_with("$argon2") {
+        let parsed = PasswordHash::new(hash).map_err(|_| PasswordError::MalformedHash)?;
+        let params = Params::try_from(&parsed).map_err(|_| PasswordError::MalformedHash)?;
+        let matches = Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok();
+        let current = parsed.algorithm == argon2::ARGON2ID_IDENT
+            && *algorithm
+                == HashAlgorithm::Argon2id {
+                    memory_kib: params.m_cost(),
+                    iterations: params.t_cost(),
+                    parallelism: params.p_cost(),
+                };
+        (matches, current)
+    } else {
+        let matches = bcrypt::verify(password, hash).map_err(|_| PasswordError::MalformedHash)?;
+        // "$2b$12$..." has the cost in its third field
+        let cost = hash.split('$').nth(2).and_then(|cost| cost.parse().ok());
+        let current = matches!(*algorithm, HashAlgorithm::Bcrypt { cost: wanted } if cost == Some(wanted));
+        (matches, current)
+    };
+
+    Ok(match (matches, current) {
+        (false, _) => Verification::Mismatch,
+        (true, true) => Verification::Match,
+        (true, false) => Verification::MatchOutdated,
+    })
+}
@@ -0,0 +1,540 @@
+//! Storage of user accounts behind the `UserRepository` trait.
+//!
+//! Handlers only ever see `web::Data<dyn UserRepository>`, so the service runs
+//! on MongoDB in production and on `InMemoryUserRepository` in tests.
+//!
+//! Deleting a user only sets its `deleted_at`. Every lookup skips such users
+//! until an admin restores them or `purge_deleted` removes them for good.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use derive_more::Display;
+use errors::AppError;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    Collation, CollationStrength, FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateOptions,
+};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::models::UserRecord;
+
+/// The collation of the unique username and email indexes. Lookups by either
+/// use it too, so they ignore case like the indexes and can use them.
+pub fn ignoring_case() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Errors returned by the storage backends.
+#[derive(Debug, Display)]
+pub enum RepositoryError {
+    /// A unique constraint rejected the write
+    #[display(fmt = "duplicate value for {}", _0)]
+    Duplicate(&'static str),
+    /// The database can't be reached right now; worth retrying later
+    #[display(fmt = "database unavailable: {}", _0)]
+    Unavailable(mongodb::error::Error),
+    #[display(fmt = "{}", _0)]
+    Database(mongodb::error::Error),
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(err: mongodb::error::Error) -> Self {
+        if is_duplicate_key(&err) {
+            return RepositoryError::Duplicate(duplicate_field(&err));
+        }
+        match *err.kind {
+            ErrorKind::ServerSelection { .. } | ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => {
+                RepositoryError::Unavailable(err)
+            }
+            _ => RepositoryError::Database(err),
+        }
+    }
+}
+
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether a write was rejected by a unique index.
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    duplicate_key_error(err).is_some()
+}
+
+// The message and details of the duplicate key error in `err`, if any
+fn duplicate_key_error(err: &mongodb::error::Error) -> Option<(&str, Option<&Document>)> {
+    match &*err.kind {
+        ErrorKind::Command(err) if err.code == DUPLICATE_KEY => Some((&err.message, None)),
+        ErrorKind::Write(WriteFailure::WriteError(err)) if err.code == DUPLICATE_KEY => {
+            Some((&err.message, err.details.as_ref()))
+        }
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .find(|err| err.code == DUPLICATE_KEY)
+            .map(|err| (err.message.as_str(), err.details.as_ref())),
+        _ => None,
+    }
+}
+
+// The field behind the unique index of a duplicate key error: from the key
+// pattern in its details if the server sent them, else from the index name
+// in its message, as in
+// "E11000 duplicate key error collection: myApp.users index: email_unique dup key: ..."
+fn duplicate_field(err: &mongodb::error::Error) -> &'static str {
+    let Some((message, details)) = duplicate_key_error(err) else {
+        return "value";
+    };
+    let index = details
+        .and_then(|details| details.get_document("keyPattern").ok())
+        .and_then(|pattern| pattern.keys().next().map(String::as_str))
+        .or_else(|| {
+            let (_, rest) = message.split_once(" index: ")?;
+            rest.split_whitespace().next()
+        })
+        .unwrap_or_default();
+    let field = ["username", "email"].into_iter().find(|field| index.starts_with(field));
+    field.unwrap_or_else(|| {
+        eprintln!("duplicate key on an unexpected index: {message}");
+        "value"
+    })
+}
+
+/// Seconds clients are asked to wait before retrying while the database is down.
+pub const RETRY_AFTER_SECS: u32 = 5;
+
+/// The driver's messages are logged, never sent to clients.
+impl From<RepositoryError> for AppError {
+    fn from(err: RepositoryError) -> Self {
+        match err {
+            RepositoryError::Duplicate(field) => AppError::conflict_on(field, format!("The {field} is already taken")),
+            RepositoryError::Unavailable(err) => {
+                eprintln!("database unavailable: {err}");
+                AppError::Unavailable {
+                    retry_after_secs: RETRY_AFTER_SECS.into(),
+                }
+            }
+            RepositoryError::Database(err) => {
+                eprintln!("database error: {err}");
+                AppError::internal(err)
+            }
+        }
+    }
+}
+
+pub type RepositoryResult<T> = Result<T, RepositoryError>;
+
+/// Field a user listing is ordered by. Ties are broken by id.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    CreatedAt,
+    Username,
+}
+
+impl SortField {
+    fn name(self) -> &'static str {
+        match self {
+            SortField::CreatedAt => "created_at",
+            SortField::Username => "username",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// The value a user is sorted by.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    /// Milliseconds since the epoch
+    CreatedAt(i64),
+    Username(String),
+}
+
+impl SortKey {
+    pub fn of(field: SortField, user: &UserRecord) -> Self {
+        match field {
+            SortField::CreatedAt => SortKey::CreatedAt(user.created_at.timestamp_millis()),
+            SortField::Username => SortKey::Username(user.username.clone()),
+        }
+    }
+
+    pub fn field(&self) -> SortField {
+        match self {
+            SortKey::CreatedAt(_) => SortField::CreatedAt,
+            SortKey::Username(_) => SortField::Username,
+        }
+    }
+
+    fn to_bson(&self) -> Bson {
+        match self {
+            SortKey::CreatedAt(millis) => Bson::DateTime(mongodb::bson::DateTime::from_millis(*millis)),
+            SortKey::Username(username) => Bson::String(username.clone()),
+        }
+    }
+}
+
+/// Which users `UserRepository::find_page` returns.
+#[derive(Clone, Debug)]
+pub struct UserQuery {
+    /// Only users whose username or email starts with this
+    pub prefix: Option<String>,
+    pub sort: SortField,
+    pub order: SortOrder,
+    /// Only users that come after this sort key and id
+    pub after: Option<(SortKey, ObjectId)>,
+    pub limit: usize,
+}
+
+impl UserQuery {
+    fn matches(&self, user: &UserRecord) -> bool {
+        let prefixed = self
+            .prefix
+            .as_deref()
+            .is_none_or(|p| user.username.starts_with(p) || user.email.starts_with(p));
+        let after = self.after.as_ref().is_none_or(|(key, id)| {
+            let position = (SortKey::of(self.sort, user), user.id);
+            match self.order {
+                SortOrder::Asc => position > (key.clone(), *id),
+                SortOrder::Desc => position < (key.clone(), *id),
+            }
+        });
+        !user.is_deleted() && prefixed && after
+    }
+
+    fn filter(&self) -> Document {
+        let mut filters = vec![doc! { "deleted_at": null }];
+        if let Some(prefix) = &self.prefix {
+            // An anchored, case-sensitive regex can use the username and email indexes
+            let pattern = format!("^{}", regex::escape(prefix));
+            filters.push(doc! {
+                "$or": [
+                    { "username": { "$regex": &pattern } },
+                    { "email": { "$regex": &pattern } },
+                ]
+            });
+        }
+        if let Some((key, id)) = &self.after {
+            let field = self.sort.name();
+            let op = match self.order {
+                SortOrder::Asc => "$gt",
+                SortOrder::Desc => "$lt",
+            };
+            let value = key.to_bson();
+            filters.push(doc! {
+                "$or": [
+                    { field: { op: &value } },
+                    { field: &value, "_id": { op: id } },
+                ]
+            });
+        }
+        doc! { "$and": filters }
+    }
+
+    fn sort_doc(&self) -> Document {
+        let direction = match self.order {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        };
+        doc! { self.sort.name(): direction, "_id": direction }
+    }
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn create(&self, user: &UserRecord) -> RepositoryResult<()>;
+
+    async fn find_by_id(&self, id: ObjectId) -> RepositoryResult<Option<UserRecord>>;
+
+    /// Finds the user called `username`, ignoring case like the unique index.
+    async fn find_by_username(&self, username: &str) -> RepositoryResult<Option<UserRecord>>;
+
+    /// Finds the user with `email`, ignoring case like the unique index.
+    async fn find_by_email(&self, email: &str) -> RepositoryResult<Option<UserRecord>>;
+
+    /// Overwrites the stored fields of the user called `username`.
+    ///
+    /// Returns `false` if there is no such user.
+    async fn update(&self, username: &str, user: &UserRecord) -> RepositoryResult<bool>;
+
+    /// Marks the user called `username` as deleted at `at`.
+    ///
+    /// Returns `false` if there is no such user.
+    async fn soft_delete(&self, username: &str, at: DateTime) -> RepositoryResult<bool>;
+
+    /// Brings back the deleted user called `username`.
+    ///
+    /// Returns `None` if there is no such deleted user.
+    async fn restore(&self, username: &str) -> RepositoryResult<Option<UserRecord>>;
+
+    /// Removes the users deleted before `before` for good and returns them.
+    async fn purge_deleted(&self, before: DateTime) -> RepositoryResult<Vec<UserRecord>>;
+
+    /// Returns up to `query.limit` users in the order of `query.sort`.
+    async fn find_page(&self, query: &UserQuery) -> RepositoryResult<Vec<UserRecord>>;
+}
+
+/// Users stored in a MongoDB collection, "users" unless configured otherwise.
+pub struct MongoUserRepository {
+    collection: Collection<UserRecord>,
+}
+
+impl MongoUserRepository {
+    pub fn new(db: &Database, collection: &str) -> Self {
+        MongoUserRepository {
+            collection: db.collection(collection),
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoUserRepository {
+    async fn create(&self, user: &UserRecord) -> RepositoryResult<()> {
+        self.collection.insert_one(user, None).await?;
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: ObjectId) -> RepositoryResult<Option<UserRecord>> {
+        Ok(self
+            .collection
+            .find_one(doc! { "_id": id, "deleted_at": null }, None)
+            .await?)
+    }
+
+    async fn find_by_username(&self, username: &str) -> RepositoryResult<Option<UserRecord>> {
+        let options = FindOneOptions::builder().collation(ignoring_case()).build();
+        Ok(self
+            .collection
+            .find_one(doc! { "username": username, "deleted_at": null }, options)
+            .await?)
+    }
+
+    async fn find_by_email(&self, email: &str) -> RepositoryResult<Option<UserRecord>> {
+        let options = FindOneOptions::builder().collation(ignoring_case()).build();
+        Ok(self
+            .collection
+            .find_one(doc! { "email": email, "deleted_at": null }, options)
+            .await?)
+    }
+
+    async fn update(&self, username: &str, user: &UserRecord) -> RepositoryResult<bool> {
+        let update_doc = doc! {
+            "$set": {
+                "first_name": &user.first_name,
+                "last_name": &user.last_name,
+                "username": &user.username,
+                "email": &user.email,
+                "email_verified": user.email_verified,
+                "role": mongodb::bson::to_bson(&user.role).expect("a role always serializes"),
+                "password" : &user.password_hash,
+                "updated_at": user.updated_at,
+            }
+        };
+        let options = UpdateOptions::builder().collation(ignoring_case()).build();
+        let result = self
+            .collection
+            .update_one(doc! { "username": username, "deleted_at": null }, update_doc, options)
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn soft_delete(&self, username: &str, at: DateTime) -> RepositoryResult<bool> {
+        let options = UpdateOptions::builder().collation(ignoring_case()).build();
+        let result = self
+            .collection
+            .update_one(
+                doc! { "username": username, "deleted_at": null },
+                doc! { "$set": { "deleted_at": at } },
+                options,
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn restore(&self, username: &str) -> RepositoryResult<Option<UserRecord>> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .collation(ignoring_case())
+            .build();
+        Ok(self
+            .collection
+            .find_one_and_update(
+                doc! { "username": username, "deleted_at": { "$ne": null } },
+                doc! { "$unset": { "deleted_at": "" } },
+                options,
+            )
+            .await?)
+    }
+
+    async fn purge_deleted(&self, before: DateTime) -> RepositoryResult<Vec<UserRecord>> {
+        // One at a time, each checked as it is deleted, so a user restored
+        // meanwhile is kept and not reported
+        let filter = doc! { "deleted_at": { "$lte": before } };
+        let mut purged = Vec::new();
+        while let Some(user) = self.collection.find_one_and_delete(filter.clone(), None).await? {
+            purged.push(user);
+        }
+        Ok(purged)
+    }
+
+    async fn find_page(&self, query: &UserQuery) -> RepositoryResult<Vec<UserRecord>> {
+        let options = FindOptions::builder()
+            .sort(query.sort_doc())
+            .limit(query.limit as i64)
+            .build();
+        let mut cursor = self.collection.find(query.filter(), options).await?;
+        let mut users = Vec::new();
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok(users)
+    }
+}
+
+/// Users kept in memory, for tests and running without a database.
+///
+/// Enforces the same case-insensitive unique username and email constraints
+/// as the MongoDB indexes, which cover deleted users too.
+#[derive(Default)]
+pub struct InMemoryUserRepository {
+    users: Mutex<HashMap<ObjectId, UserRecord>>,
+}
+
+impl InMemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// Equal under `ignoring_case`, near enough for tests
+fn same(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// Rejects `user` if another user has the same username or email, ignoring case
+fn check_unique(users: &HashMap<ObjectId, UserRecord>, user: &UserRecord) -> RepositoryResult<()> {
+    let others = || users.values().filter(|u| u.id != user.id);
+    if others().any(|u| same(&u.username, &user.username)) {
+        return Err(RepositoryError::Duplicate("username"));
+    }
+    if others().any(|u| same(&u.email, &user.email)) {
+        return Err(RepositoryError::Duplicate("email"));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    async fn create(&self, user: &UserRecord) -> RepositoryResult<()> {
+        let mut users = self.users.lock().unwrap();
+        check_unique(&users, user)?;
+        users.insert(user.id, user.clone());
+        Ok(())
+    }
+
+    async fn find_by_id(&self, id: ObjectId) -> RepositoryResult<Option<UserRecord>> {
+        let users = self.users.lock().unwrap();
+        Ok(users.get(&id).filter(|u| !u.is_deleted()).cloned())
+    }
+
+    async fn find_by_username(&self, username: &str) -> RepositoryResult<Option<UserRecord>> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .values()
+            .find(|u| !u.is_deleted() && same(&u.username, username))
+            .cloned())
+    }
+
+    async fn find_by_email(&self, email: &str) -> RepositoryResult<Option<UserRecord>> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .values()
+            .find(|u| !u.is_deleted() && same(&u.email, email))
+            .cloned())
+    }
+
+    async fn update(&self, username: &str, user: &UserRecord) -> RepositoryResult<bool> {
+        let mut users = self.users.lock().unwrap();
+        check_unique(&users, user)?;
+        match users
+            .values_mut()
+            .find(|u| !u.is_deleted() && same(&u.username, username))
+        {
+            Some(stored) => {
+                stored.first_name = user.first_name.clone();
+                stored.last_name = user.last_name.clone();
+                stored.username = user.username.clone();
+                stored.email = user.email.clone();
+                stored.email_verified = user.email_verified;
+                stored.role = user.role;
+                stored.password_hash = user.password_hash.clone();
+                stored.updated_at = user.updated_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn soft_delete(&self, username: &str, at: DateTime) -> RepositoryResult<bool> {
+        let mut users = self.users.lock().unwrap();
+        match users
+            .values_mut()
+            .find(|u| !u.is_deleted() && same(&u.username, username))
+        {
+            Some(stored) => {
+                stored.deleted_at = Some(at);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn restore(&self, username: &str) -> RepositoryResult<Option<UserRecord>> {
+        let mut users = self.users.lock().unwrap();
+        match users
+            .values_mut()
+            .find(|u| u.is_deleted() && same(&u.username, username))
+        {
+            Some(stored) => {
+                stored.deleted_at = None;
+                Ok(Some(stored.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn purge_deleted(&self, before: DateTime) -> RepositoryResult<Vec<UserRecord>> {
+        let mut users = self.users.lock().unwrap();
+        let expired: Vec<ObjectId> = users
+            .values()
+            .filter(|u| u.deleted_at.is_some_and(|at| at <= before))
+            .map(|u| u.id)
+            .collect();
+        Ok(expired.iter().filter_map(|id| users.remove(id)).collect())
+    }
+
+    async fn find_page(&self, query: &UserQuery) -> RepositoryResult<Vec<UserRecord>> {
+        let users = self.users.lock().unwrap();
+        let mut page: Vec<_> = users.values().filter(|u| query.matches(u)).cloned().collect();
+        page.sort_by_key(|u| (SortKey::of(query.sort, u), u.id));
+        if query.order == SortOrder::Desc {
+            page.reverse();
+        }
+        page.truncate(query.limit);
+        Ok(page)
+    }
+}
@@ -0,0 +1,102 @@
+//! Roles and what they allow.
+//!
+//! Every user has one `Role`, stored on the user document. Users can always
+//! manage their own account; acting on somebody else's needs a role that
+//! allows the `Action` and ranks above the other account's role. Routes that
+//! are off limits below a role take a `RequireRole<R>` argument.
+use std::marker::PhantomData;
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use errors::AppError;
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::models::UserRecord;
+use crate::repository::UserRepository;
+use crate::session::SignedInUser;
+
+/// Roles in increasing order of privilege.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Things done to accounts other than one's own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    EditUser,
+    DeleteUser,
+    ChangeRole,
+}
+
+impl Role {
+    pub fn can(self, action: Action) -> bool {
+        match action {
+            Action::EditUser => self >= Role::Moderator,
+            Action::DeleteUser | Action::ChangeRole => self == Role::Admin,
+        }
+    }
+
+    /// Whether a user with this role may do `action` to an account with the `target` role.
+    pub fn can_act_on(self, action: Action, target: Role) -> bool {
+        self.can(action) && self > target
+    }
+}
+
+/// The least role a `RequireRole` guard lets through.
+pub trait MinimumRole {
+    const ROLE: Role;
+}
+
+pub struct Moderator;
+
+impl MinimumRole for Moderator {
+    const ROLE: Role = Role::Moderator;
+}
+
+pub struct Admin;
+
+impl MinimumRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Extractor for the signed in user's record, rejecting users whose role is
+/// below `R` with 403 Forbidden.
+///
+/// The role is read from the database on every request, so a demotion takes
+/// effect right away rather than when the session ends.
+pub struct RequireRole<R> {
+    pub user: UserRecord,
+    role: PhantomData<R>,
+}
+
+impl<R: MinimumRole> FromRequest for RequireRole<R> {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let signed_in = SignedInUser::extract(&req).await?;
+            let users = req
+                .app_data::<web::Data<dyn UserRepository>>()
+                .ok_or_else(|| AppError::internal("user repository is not configured"))?;
+            let user = users
+                .find_by_id(signed_in.user_id)
+                .await?
+                // The account was deleted while the session lived on
+                .ok_or_else(|| AppError::unauthorized("You must be signed in"))?;
+            if user.role < R::ROLE {
+                return Err(AppError::forbidden("Your role does not allow this"));
+            }
+            Ok(RequireRole {
+                user,
+                role: PhantomData,
+            })
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! Server-side sessions for signed-in users.
+//!
+//! The browser only ever holds an encrypted cookie carrying a random session id.
+//! The session record itself (user id, issue time, expiry) lives in a
+//! `SessionStore` on the server, so signing out or expiring a session takes
+//! effect immediately.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::cookie::{time::Duration, Cookie, CookieJar, Key, SameSite};
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use async_trait::async_trait;
+use errors::AppError;
+use futures_util::future::LocalBoxFuture;
+use mongodb::bson::{doc, oid::ObjectId, DateTime};
+use mongodb::{Collection, Database};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SessionConfig;
+use crate::jwt::{bearer_token, BearerToken, Claims};
+use crate::repository::RepositoryResult;
+
+pub const SESSION_COOKIE: &str = "session";
+pub const SESSIONS_COLL_NAME: &str = "sessions";
+// How long a session stays valid after sign in
+const SESSION_TTL_SECS: i64 = 60 * 60 * 24;
+
+/// A session record as stored in the "sessions" collection.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub user_id: ObjectId,
+    pub username: String,
+    pub issued_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+impl Session {
+    fn is_expired(&self) -> bool {
+        self.expires_at <= DateTime::now()
+    }
+}
+
+/// Key used to encrypt and authenticate the session cookie.
+#[derive(Clone)]
+pub struct SessionKey(pub Key);
+
+impl SessionKey {
+    /// The configured key, or a throwaway one if there is none.
+    ///
+    /// A generated key means every session is lost when the server restarts.
+    pub fn from_config(config: &SessionConfig) -> Self {
+        match &config.key {
+            Some(secret) => SessionKey(
+                Key::try_from(secret.as_bytes()).expect("the length of session.key is validated"),
+            ),
+            None => SessionKey(Key::generate()),
+        }
+    }
+}
+
+/// Storage of session records.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn insert(&self, session: &Session) -> RepositoryResult<()>;
+
+    async fn find(&self, id: &str) -> RepositoryResult<Option<Session>>;
+
+    async fn delete(&self, id: &str) -> RepositoryResult<()>;
+
+    /// Ends every session of a user.
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()>;
+}
+
+/// Sessions stored in the "sessions" collection.
+pub struct MongoSessionStore {
+    collection: Collection<Session>,
+}
+
+impl MongoSessionStore {
+    pub fn new(db: &Database) -> Self {
+        MongoSessionStore {
+            collection: db.collection(SESSIONS_COLL_NAME),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MongoSessionStore {
+    async fn insert(&self, session: &Session) -> RepositoryResult<()> {
+        self.collection.insert_one(session, None).await?;
+        Ok(())
+    }
+
+    async fn find(&self, id: &str) -> RepositoryResult<Option<Session>> {
+        Ok(self.collection.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    async fn delete(&self, id: &str) -> RepositoryResult<()> {
+        self.collection.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()> {
+        self.collection
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Sessions kept in memory, for tests and running without a database.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn insert(&self, session: &Session) -> RepositoryResult<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    async fn find(&self, id: &str) -> RepositoryResult<Option<Session>> {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    async fn delete(&self, id: &str) -> RepositoryResult<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn delete_for_user(&self, user_id: ObjectId) -> RepositoryResult<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.user_id != user_id);
+        Ok(())
+    }
+}
+
+/// Creates a new session for the given user and stores it.
+pub async fn start_session(
+    store: &dyn SessionStore,
+    user_id: ObjectId,
+    username: &str,
+) -> RepositoryResult<Session> {
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect();
+    let issued_at = DateTime::now();
+    let session = Session {
+        id,
+        user_id,
+        username: username.to_owned(),
+        issued_at,
+        expires_at: DateTime::from_millis(issued_at.timestamp_millis() + SESSION_TTL_SECS * 1000),
+    };
+    store.insert(&session).await?;
+    Ok(session)
+}
+
+/// Looks up a session by id, ignoring (and removing) expired ones.
+pub async fn find_session(store: &dyn SessionStore, id: &str) -> RepositoryResult<Option<Session>> {
+    match store.find(id).await? {
+        Some(session) if session.is_expired() => {
+            store.delete(id).await?;
+            Ok(None)
+        }
+        session => Ok(session),
+    }
+}
+
+/// Builds the encrypted cookie handed to the browser for this session; with
+/// `secure`, the browser only sends it over HTTPS.
+pub fn session_cookie(key: &SessionKey, session: &Session, secure: bool) -> Cookie<'static> {
+    let cookie = Cookie::build(SESSION_COOKIE, session.id.clone())
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .max_age(Duration::seconds(SESSION_TTL_SECS))
+        .finish();
+    let mut jar = CookieJar::new();
+    jar.private_mut(&key.0).add(cookie);
+    jar.delta().next().cloned().expect("cookie was just added")
+}
+
+/// Cookie that tells the browser to forget the session cookie.
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    cookie
+}
+
+/// Decrypts the session cookie and returns the session id it carries.
+fn session_id(req: &HttpRequest, key: &SessionKey) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(SESSION_COOKIE)?);
+    let id = jar.private(&key.0).get(SESSION_COOKIE)?.value().to_owned();
+    Some(id)
+}
+
+/// The user behind the current request's session.
+///
+/// Use it as a handler argument to make a route require a signed-in user;
+/// requests without a valid session cookie or bearer token are rejected with
+/// 401 Unauthorized.
+///
+/// Behind `IsUserSignedInMiddleware`, the user is taken from the request
+/// extensions instead of being looked up again.
+#[derive(Clone, Debug)]
+pub struct SignedInUser {
+    pub user_id: ObjectId,
+    pub username: String,
+    pub credential: Credential,
+}
+
+/// How the user proved who they are on this request.
+#[derive(Clone, Debug)]
+pub enum Credential {
+    /// The session cookie, carrying the session's id
+    Session(String),
+    /// An `Authorization: Bearer` access token
+    AccessToken(Claims),
+}
+
+impl FromRequest for SignedInUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Already resolved by `IsUserSignedInMiddleware`
+        if let Some(user) = req.extensions().get::<SignedInUser>() {
+            let user = user.clone();
+            return Box::pin(async move { Ok(user) });
+        }
+
+        let req = req.clone();
+        Box::pin(async move {
+            // Clients sending a bearer token are authenticated by it alone
+            if bearer_token(&req).is_some() {
+                let BearerToken(claims) = BearerToken::extract(&req).await?;
+                let user_id = ObjectId::parse_str(&claims.sub)
+                    .map_err(|_| AppError::unauthorized("Invalid or expired access token"))?;
+                return Ok(SignedInUser {
+                    user_id,
+                    username: claims.username.clone(),
+                    credential: Credential::AccessToken(claims),
+                });
+            }
+
+            let key = req
+                .app_data::<web::Data<SessionKey>>()
+                .ok_or_else(|| AppError::internal("session key is not configured"))?;
+            let store = req
+                .app_data::<web::Data<dyn SessionStore>>()
+                .ok_or_else(|| AppError::internal("session store is not configured"))?;
+
+            let id = session_id(&req, key)
+                .ok_or_else(|| AppError::unauthorized("You must be signed in"))?;
+            let session = find_session(store.get_ref(), &id)
+                .await?
+                .ok_or_else(|| AppError::unauthorized("Your session has expired"))?;
+
+            Ok(SignedInUser {
+                user_id: session.user_id,
+                username: session.username,
+                credential: Credential::Session(session.id),
+            })
+        })
+    }
+}
@@ -0,0 +1,1259 @@
+//! HTTP level tests running the whole service on the in-memory stores.
+use actix_web::body::BoxBody;
+use actix_web::cookie::{Cookie, Key};
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, web, App};
+use serde_json::{json, Value};
+
+use super::*;
+use crate::audit::InMemoryAuditLog;
+use crate::config::ConfigError;
+use crate::mail::InMemoryMailer;
+
+const PASSWORD: &str = "Correct horse 1";
+// The frontend allowed to call the test app from another origin
+const SPA: &str = "https://app.example.com";
+const FAST_HASHING: HashAlgorithm = HashAlgorithm::Argon2id {
+    memory_kib: 64,
+    iterations: 1,
+    parallelism: 1,
+};
+
+// Handles on the backends behind a test app
+struct TestEnv {
+    outbox: Arc<InMemoryMailer>,
+    audit: Arc<InMemoryAuditLog>,
+    users: web::Data<dyn UserRepository>,
+}
+
+async fn init_app() -> (
+    impl Service<actix_http::Request, Response = ServiceResponse<EitherBody<EitherBody<BoxBody>>>, Error = Error>,
+    TestEnv,
+) {
+    init_app_with(Stores::in_memory()).await
+}
+
+async fn init_app_with(
+    mut stores: Stores,
+) -> (
+    impl Service<actix_http::Request, Response = ServiceResponse<EitherBody<EitherBody<BoxBody>>>, Error = Error>,
+    TestEnv,
+) {
+    let outbox = Arc::new(InMemoryMailer::new());
+    let audit = Arc::new(InMemoryAuditLog::new());
+    stores.audit = web::Data::from(audit.clone() as Arc<dyn AuditLog>);
+    let env = TestEnv {
+        outbox: outbox.clone(),
+        audit,
+        users: stores.users.clone(),
+    };
+    let mut config = Config::default();
+    config.cors.allowed_origins = vec![SPA.to_owned()];
+    let config = web::Data::new(config);
+    let limits = rate_limit(&config.rate_limit, Arc::new(InMemoryStore::new()));
+    let emails = web::Data::new(AccountEmails::new(outbox, "http://test"));
+    // Cheap settings, so the tests don't spend their time hashing
+    let passwords = web::Data::new(Passwords::new(FAST_HASHING).unwrap());
+    let session_key = web::Data::new(SessionKey(Key::generate()));
+    let jwt_keys = web::Data::new(JwtKeys::hs256(b"test secret", "test", "test-api"));
+    let app = App::new()
+        .wrap(limits)
+        .wrap(cors(&config.cors))
+        .configure(configure(stores, config, emails, passwords, session_key, jwt_keys));
+    (test::init_service(app).await, env)
+}
+
+// Changes a role directly in the repository, as there is no admin to do it yet
+async fn set_role(env: &TestEnv, username: &str, role: Role) {
+    let mut record = env.users.find_by_username(username).await.unwrap().unwrap();
+    record.set_role(role);
+    env.users.update(username, &record).await.unwrap();
+}
+
+// The token in the link of the last email sent to `to`
+fn mailed_token(outbox: &InMemoryMailer, to: &str) -> String {
+    let email = outbox
+        .sent()
+        .into_iter()
+        .rev()
+        .find(|email| email.to == to)
+        .expect("an email was sent");
+    let start = email.body.find("token=").expect("the email has a link") + "token=".len();
+    email.body[start..].split_whitespace().next().unwrap().to_owned()
+}
+
+fn user_form(username: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("first_name", "Jane".to_owned()),
+        ("last_name", "Doe".to_owned()),
+        ("username", username.to_owned()),
+        ("email", format!("{username}@example.com")),
+        ("password", PASSWORD.to_owned()),
+        ("confirm_password", PASSWORD.to_owned()),
+    ]
+}
+
+// Adds a user and verifies its email address
+async fn add(
+    app: &impl Service<actix_http::Request, Response = ServiceResponse<EitherBody<EitherBody<BoxBody>>>, Error = Error>,
+    env: &TestEnv,
+    username: &str,
+) {
+    let req = test::TestRequest::post()
+        .uri("/add_user")
+        .set_form(user_form(username))
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let token = mailed_token(&env.outbox, &format!("{username}@example.com"));
+    let req = test::TestRequest::post()
+        .uri("/verify_email")
+        .set_json(json!({ "token": token }))
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+async fn sign_in_cookie(
+    app: &impl Service<actix_http::Request, Response = ServiceResponse<EitherBody<EitherBody<BoxBody>>>, Error = Error>,
+    username: &str,
+) -> Cookie<'static> {
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": username, "password": PASSWORD }))
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.response()
+        .cookies()
+        .find(|c| c.name() == session::SESSION_COOKIE)
+        .expect("sign in sets the session cookie")
+        .into_owned()
+}
+
+#[actix_web::test]
+async fn add_and_get_user() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let req = test::TestRequest::get().uri("/get_user/jane").to_request();
+    let user: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(user["username"], "jane");
+    assert!(user["id"].is_string());
+    assert!(user["created_at"].is_string());
+    // Anyone can look a user up, so contact details and the role stay private
+    assert!(user.get("email").is_none());
+    assert!(user.get("email_verified").is_none());
+    assert!(user.get("role").is_none());
+    // Neither the hash nor the confirmation ever leave the server
+    assert!(user.get("password").is_none());
+    assert!(user.get("password_hash").is_none());
+    assert!(user.get("confirm_password").is_none());
+
+    let req = test::TestRequest::get().uri("/get_user/nobody").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn add_user_rejects_mismatched_passwords() {
+    let (app, _) = init_app().await;
+    let mut form = user_form("jane");
+    form[5].1 = "something else".to_owned();
+    let req = test::TestRequest::post().uri("/add_user").set_form(form).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn add_user_reports_every_invalid_field() {
+    let (app, _) = init_app().await;
+    let form = [
+        ("first_name", ""),
+        ("last_name", "Doe"),
+        ("username", "j@ne"),
+        ("email", "not an email"),
+        ("password", "short"),
+        ("confirm_password", "short"),
+    ];
+    let req = test::TestRequest::post().uri("/add_user").set_form(form).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+
+    let body: Value = test::read_body_json(res).await;
+    let fields: Vec<_> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["first_name", "username", "email", "password"]);
+}
+
+#[actix_web::test]
+async fn add_user_normalizes_the_email() {
+    let (app, env) = init_app().await;
+    let mut form = user_form("jane");
+    form[3].1 = "  Jane@Example.COM ".to_owned();
+    let req = test::TestRequest::post().uri("/add_user").set_form(form).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let user = env.users.find_by_username("jane").await.unwrap().unwrap();
+    assert_eq!(user.email, "jane@example.com");
+}
+
+#[actix_web::test]
+async fn sign_in_accepts_form_credentials() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_form([("username", "jane"), ("password", PASSWORD)])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn sign_in_failures_look_the_same() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let wrong_password = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": "wrong" }))
+        .to_request();
+    let res = test::call_service(&app, wrong_password).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let mut wrong_password: Value = test::read_body_json(res).await;
+
+    let unknown_user = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "nobody", "password": "wrong" }))
+        .to_request();
+    let res = test::call_service(&app, unknown_user).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let mut unknown_user: Value = test::read_body_json(res).await;
+    // Every problem document has its own correlation id
+    wrong_password["correlation_id"].take();
+    unknown_user["correlation_id"].take();
+    assert_eq!(unknown_user, wrong_password);
+}
+
+#[actix_web::test]
+async fn repeated_failures_lock_the_account() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    for _ in 0..5 {
+        let req = test::TestRequest::post()
+            .uri("/sign_in")
+            .set_json(json!({ "username": "jane", "password": "wrong" }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    // Even the right password is refused while locked out
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key(header::RETRY_AFTER));
+}
+
+#[actix_web::test]
+async fn signing_into_an_own_account_keeps_the_ip_counter() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    add(&app, &env, "john").await;
+    let attempt = |username: &str, password: &str| {
+        test::TestRequest::post()
+            .uri("/sign_in")
+            .peer_addr("10.0.0.9:4000".parse().unwrap())
+            .set_json(json!({ "username": username, "password": password }))
+            .to_request()
+    };
+
+    for _ in 0..4 {
+        test::call_service(&app, attempt("john", "guess")).await;
+    }
+    let res = test::call_service(&app, attempt("jane", PASSWORD)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, attempt("john", "guess")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // The fifth failure from the IP locks it out, whichever accounts it tried
+    let res = test::call_service(&app, attempt("jane", PASSWORD)).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn account_routes_require_a_session() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let req = test::TestRequest::delete().uri("/users/jane").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "unauthorized");
+}
+
+#[actix_web::test]
+async fn allow_listed_routes_need_no_session() {
+    let guard = IsUserSignedInMiddleware::new().allow("/area/public").allow("/area/docs/*");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(SessionKey(Key::generate())))
+            .app_data(Stores::in_memory().sessions)
+            .service(
+                web::scope("/area")
+                    .wrap(guard)
+                    .route("/public", web::get().to(|| async { "public" }))
+                    .route("/public/more", web::get().to(|| async { "not public" }))
+                    .route("/docs/{page}", web::get().to(|| async { "docs" }))
+                    .route("/private", web::get().to(|| async { "private" })),
+            ),
+    )
+    .await;
+    let call = |uri: &str| test::call_service(&app, test::TestRequest::get().uri(uri).to_request());
+
+    for uri in ["/area/public", "/area/docs/intro"] {
+        assert_eq!(call(uri).await.status(), StatusCode::OK, "{uri}");
+    }
+    // Only a trailing `*` matches more than the exact path
+    for uri in ["/area/private", "/area/public/more"] {
+        let res = call(uri).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "unauthorized");
+    }
+}
+
+#[actix_web::test]
+async fn users_can_only_manage_their_own_account() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    add(&app, &env, "john").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+
+    let req = test::TestRequest::delete()
+        .uri("/users/john")
+        .cookie(cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::delete()
+        .uri("/users/jane")
+        .cookie(cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/get_user/jane").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn edit_user_only_changes_the_given_fields() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+
+    let req = test::TestRequest::patch()
+        .uri("/users/jane")
+        .cookie(cookie)
+        .set_json(json!({ "first_name": "Janet" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["first_name"], "Janet");
+    assert_eq!(user["last_name"], "Doe");
+    assert_eq!(user["email"], "jane@example.com");
+}
+
+#[actix_web::test]
+async fn edit_user_can_rename_unless_the_name_is_taken() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    add(&app, &env, "john").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+
+    let rename = |from: &str, to: &str| {
+        test::TestRequest::patch()
+            .uri(&format!("/users/{from}"))
+            .cookie(cookie.clone())
+            .set_json(json!({ "username": to }))
+            .to_request()
+    };
+    let res = test::call_service(&app, rename("jane", "john")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["field"], "username");
+
+    let res = test::call_service(&app, rename("jane", "janet")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // The session still belongs to the renamed account
+    let res = test::call_service(&app, rename("janet", "jane")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn usernames_and_emails_are_unique_ignoring_case() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    add(&app, &env, "john").await;
+
+    let req = test::TestRequest::post()
+        .uri("/add_user")
+        .set_form(user_form("JANE"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["field"], "username");
+
+    let mut form = user_form("janet");
+    form[3].1 = "Jane@Example.com".to_owned();
+    let req = test::TestRequest::post().uri("/add_user").set_form(form).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["field"], "email");
+
+    let cookie = sign_in_cookie(&app, "jane").await;
+    let edit = |changes: Value| {
+        test::TestRequest::patch()
+            .uri("/users/jane")
+            .cookie(cookie.clone())
+            .set_json(changes)
+            .to_request()
+    };
+    let res = test::call_service(&app, edit(json!({ "username": "John" }))).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["field"], "username");
+
+    let res = test::call_service(&app, edit(json!({ "email": "john@example.com" }))).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["field"], "email");
+
+    // Changing only the case of your own name is no conflict
+    let res = test::call_service(&app, edit(json!({ "username": "Jane" }))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn usernames_and_emails_are_found_ignoring_case() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    // As stored by a version that didn't lowercase emails
+    let mut record = env.users.find_by_username("jane").await.unwrap().unwrap();
+    record.email = "Jane@Example.com".to_owned();
+    env.users.update("jane", &record).await.unwrap();
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "JANE", "password": PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(env.users.find_by_email("jane@example.com").await.unwrap().is_some());
+
+    let req = test::TestRequest::post()
+        .uri("/password_reset")
+        .set_json(json!({ "email": "jane@example.com" }))
+        .to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(env.outbox.sent().last().unwrap().to, "Jane@Example.com");
+
+    let cookie = sign_in_cookie(&app, "jane").await;
+    let req = test::TestRequest::patch()
+        .uri("/users/Jane")
+        .cookie(cookie)
+        .set_json(json!({ "first_name": "Janet" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn change_password_requires_the_current_password() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+
+    let change = |current: &str| {
+        test::TestRequest::post()
+            .uri("/users/jane/password")
+            .cookie(cookie.clone())
+            .set_json(json!({
+                "current_password": current,
+                "new_password": "Battery staple 2",
+                "confirm_password": "Battery staple 2",
+            }))
+            .to_request()
+    };
+    let res = test::call_service(&app, change("wrong")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let other_session = sign_in_cookie(&app, "jane").await;
+    let res = test::call_service(&app, change(PASSWORD)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let renewed = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == session::SESSION_COOKIE)
+        .expect("the caller gets a new session")
+        .into_owned();
+
+    // Every session from before the change is over, the caller's new one works
+    let edit = |cookie: Cookie<'static>| {
+        test::TestRequest::patch()
+            .uri("/users/jane")
+            .cookie(cookie)
+            .set_json(json!({ "first_name": "Janet" }))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, edit(other_session)).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(test::call_service(&app, edit(cookie.clone())).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(test::call_service(&app, edit(renewed)).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": "Battery staple 2" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn sign_out_invalidates_the_session() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+    assert_eq!(cookie.secure(), Some(true));
+    assert_eq!(cookie.http_only(), Some(true));
+
+    // A link or image can't sign anyone out
+    let req = test::TestRequest::get().uri("/sign_out").cookie(cookie.clone()).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::post().uri("/sign_out").cookie(cookie.clone()).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // The old cookie is useless even if the browser keeps sending it
+    let req = test::TestRequest::post().uri("/sign_out").cookie(cookie).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn forged_session_cookie_is_rejected() {
+    let (app, _) = init_app().await;
+    let req = test::TestRequest::post()
+        .uri("/sign_out")
+        .cookie(Cookie::new(session::SESSION_COOKIE, "forged"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn bearer_tokens_authenticate_and_rotate() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    add(&app, &env, "john").await;
+
+    let req = test::TestRequest::post()
+        .uri("/token")
+        .set_json(json!({ "grant_type": "password", "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let tokens: Value = test::call_and_read_body_json(&app, req).await;
+    let access_token = tokens["access_token"].as_str().unwrap().to_owned();
+    let refresh_token = tokens["refresh_token"].as_str().unwrap().to_owned();
+
+    let req = test::TestRequest::delete()
+        .uri("/users/john")
+        .insert_header((header::AUTHORIZATION, format!("Bearer {access_token}")))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let refresh = |token: &str| {
+        test::TestRequest::post()
+            .uri("/token")
+            .set_json(json!({ "grant_type": "refresh_token", "refresh_token": token }))
+            .to_request()
+    };
+    let rotated: Value = test::call_and_read_body_json(&app, refresh(&refresh_token)).await;
+    let rotated = rotated["refresh_token"].as_str().unwrap().to_owned();
+    assert_ne!(rotated, refresh_token);
+
+    // Replaying the old refresh token revokes the rotated one as well
+    let res = test::call_service(&app, refresh(&refresh_token)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, refresh(&rotated)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn sign_out_revokes_the_access_token() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let req = test::TestRequest::post()
+        .uri("/token")
+        .set_json(json!({ "grant_type": "password", "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let tokens: Value = test::call_and_read_body_json(&app, req).await;
+    let bearer = format!("Bearer {}", tokens["access_token"].as_str().unwrap());
+
+    let request = || {
+        test::TestRequest::post()
+            .uri("/sign_out")
+            .insert_header((header::AUTHORIZATION, bearer.clone()))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, request()).await.status(), StatusCode::OK);
+    assert_eq!(
+        test::call_service(&app, request()).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[actix_web::test]
+async fn list_users_pages_with_a_cursor() {
+    let (app, env) = init_app().await;
+    for username in ["bob", "amy", "cid", "bea"] {
+        add(&app, &env, username).await;
+    }
+    set_role(&env, "amy", Role::Moderator).await;
+    let cookie = sign_in_cookie(&app, "amy").await;
+    let list = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/users?{query}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let usernames = |page: &Value| -> Vec<String> {
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| u["username"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let page: Value = test::call_and_read_body_json(&app, list("sort=username&limit=2")).await;
+    assert_eq!(usernames(&page), ["amy", "bea"]);
+    let cursor = page["next_cursor"].as_str().unwrap().to_owned();
+    let page: Value = test::call_and_read_body_json(&app, list(&format!("sort=username&limit=2&cursor={cursor}"))).await;
+    assert_eq!(usernames(&page), ["bob", "cid"]);
+    assert!(page["next_cursor"].is_null());
+
+    let page: Value = test::call_and_read_body_json(&app, list("order=desc")).await;
+    assert_eq!(usernames(&page), ["bea", "cid", "amy", "bob"]);
+
+    let page: Value = test::call_and_read_body_json(&app, list("sort=username&q=b")).await;
+    assert_eq!(usernames(&page), ["bea", "bob"]);
+
+    // A cursor only resumes the listing it came from
+    let res = test::call_service(&app, list(&format!("cursor={cursor}"))).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = test::call_service(&app, list("cursor=garbage")).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: Value = test::read_body_json(res).await;
+    assert_eq!(problem["errors"][0]["field"], "cursor");
+
+    let req = test::TestRequest::get().uri("/users").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let cookie = sign_in_cookie(&app, "bob").await;
+    let req = test::TestRequest::get().uri("/users").cookie(cookie).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn sign_in_requires_a_verified_email() {
+    let (app, env) = init_app().await;
+    let req = test::TestRequest::post()
+        .uri("/add_user")
+        .set_form(user_form("jane"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let signing_in = || {
+        test::TestRequest::post()
+            .uri("/sign_in")
+            .set_json(json!({ "username": "jane", "password": PASSWORD }))
+            .to_request()
+    };
+    let res = test::call_service(&app, signing_in()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let verify = |token: &str| {
+        test::TestRequest::post()
+            .uri("/verify_email")
+            .set_json(json!({ "token": token }))
+            .to_request()
+    };
+    let token = mailed_token(&env.outbox, "jane@example.com");
+    assert_eq!(test::call_service(&app, verify(&token)).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, signing_in()).await.status(), StatusCode::OK);
+
+    // Tokens only work once
+    let res = test::call_service(&app, verify(&token)).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: Value = test::read_body_json(res).await;
+    assert_eq!(problem["errors"][0]["code"], "invalid_token");
+}
+
+#[actix_web::test]
+async fn changing_the_email_sends_a_new_verification() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+
+    let req = test::TestRequest::patch()
+        .uri("/users/jane")
+        .cookie(cookie)
+        .set_json(json!({ "email": "janet@example.com" }))
+        .to_request();
+    let user: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(user["email_verified"], false);
+    mailed_token(&env.outbox, "janet@example.com");
+}
+
+#[actix_web::test]
+async fn password_reset_replaces_the_password_and_ends_sessions() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let cookie = sign_in_cookie(&app, "jane").await;
+    let sent = env.outbox.sent().len();
+
+    let request_reset = |email: &str| {
+        test::TestRequest::post()
+            .uri("/password_reset")
+            .set_json(json!({ "email": email }))
+            .to_request()
+    };
+    // Unknown addresses get the same answer, but no email
+    let res = test::call_service(&app, request_reset("nobody@example.com")).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert_eq!(env.outbox.sent().len(), sent);
+    let res = test::call_service(&app, request_reset("Jane@Example.com")).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let token = mailed_token(&env.outbox, "jane@example.com");
+
+    let confirm = |password: &str| {
+        test::TestRequest::post()
+            .uri("/password_reset/confirm")
+            .set_json(json!({ "token": token, "new_password": password, "confirm_password": password }))
+            .to_request()
+    };
+    let res = test::call_service(&app, confirm("weak")).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = test::call_service(&app, confirm("Battery staple 2")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, confirm("Battery staple 3")).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let req = test::TestRequest::post().uri("/sign_out").cookie(cookie).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": "Battery staple 2" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn roles_decide_who_can_manage_other_accounts() {
+    let (app, env) = init_app().await;
+    for username in ["ada", "moe", "bob"] {
+        add(&app, &env, username).await;
+    }
+    set_role(&env, "ada", Role::Admin).await;
+    set_role(&env, "moe", Role::Moderator).await;
+    let ada = sign_in_cookie(&app, "ada").await;
+    let moe = sign_in_cookie(&app, "moe").await;
+    let bob = sign_in_cookie(&app, "bob").await;
+
+    let edit = |cookie: &Cookie<'static>, username: &str| {
+        test::TestRequest::patch()
+            .uri(&format!("/users/{username}"))
+            .cookie(cookie.clone())
+            .set_json(json!({ "last_name": "Smith" }))
+            .to_request()
+    };
+    let delete = |cookie: &Cookie<'static>, username: &str| {
+        test::TestRequest::delete()
+            .uri(&format!("/users/{username}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let set_role = |cookie: &Cookie<'static>, username: &str, role: &str| {
+        test::TestRequest::put()
+            .uri(&format!("/users/{username}/role"))
+            .cookie(cookie.clone())
+            .set_json(json!({ "role": role }))
+            .to_request()
+    };
+
+    // Moderators edit users, but neither delete them nor touch admins
+    assert_eq!(test::call_service(&app, edit(&bob, "moe")).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, edit(&moe, "bob")).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, edit(&moe, "ada")).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, delete(&moe, "bob")).await.status(), StatusCode::FORBIDDEN);
+
+    // Only admins hand out roles, and not to themselves
+    let res = test::call_service(&app, set_role(&moe, "bob", "moderator")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, set_role(&ada, "ada", "user")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, set_role(&ada, "moe", "user")).await;
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["role"], "user");
+    // The demotion applies to the running session
+    assert_eq!(test::call_service(&app, edit(&moe, "bob")).await.status(), StatusCode::FORBIDDEN);
+
+    assert_eq!(test::call_service(&app, delete(&ada, "bob")).await.status(), StatusCode::OK);
+    let req = test::TestRequest::post().uri("/sign_out").cookie(bob).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn deleted_users_can_be_restored_until_purged() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "ada").await;
+    add(&app, &env, "bob").await;
+    set_role(&env, "ada", Role::Admin).await;
+    let ada = sign_in_cookie(&app, "ada").await;
+    let bob = sign_in_cookie(&app, "bob").await;
+
+    let req = test::TestRequest::delete()
+        .uri("/users/bob")
+        .cookie(bob.clone())
+        .peer_addr("10.0.0.7:4000".parse().unwrap())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get().uri("/get_user/bob").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "bob", "password": PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let restore = |cookie: &Cookie<'static>| {
+        test::TestRequest::post()
+            .uri("/users/bob/restore")
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, restore(&bob)).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(test::call_service(&app, restore(&ada)).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, restore(&ada)).await.status(), StatusCode::NOT_FOUND);
+    let req = test::TestRequest::get().uri("/get_user/bob").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // Purging only removes users deleted before the retention period
+    let req = test::TestRequest::delete().uri("/users/bob").cookie(ada.clone()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let audit: Arc<dyn AuditLog> = env.audit.clone();
+    purge_expired_users(env.users.get_ref(), audit.as_ref(), 1).await;
+    assert_eq!(test::call_service(&app, restore(&ada)).await.status(), StatusCode::OK);
+    let req = test::TestRequest::delete().uri("/users/bob").cookie(ada.clone()).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    purge_expired_users(env.users.get_ref(), audit.as_ref(), 0).await;
+    assert_eq!(test::call_service(&app, restore(&ada)).await.status(), StatusCode::NOT_FOUND);
+
+    let trail: Vec<_> = env
+        .audit
+        .events()
+        .into_iter()
+        .filter(|event| event.username == "bob")
+        .collect();
+    let actions: Vec<_> = trail.iter().map(|event| event.action).collect();
+    assert_eq!(
+        actions,
+        [
+            AuditAction::Created,
+            AuditAction::SignedIn,
+            AuditAction::Deleted,
+            AuditAction::Restored,
+            AuditAction::Deleted,
+            AuditAction::Restored,
+            AuditAction::Deleted,
+            AuditAction::Purged,
+        ]
+    );
+    assert_eq!(trail[2].actor.as_deref(), Some("bob"));
+    assert_eq!(trail[2].ip.as_deref(), Some("10.0.0.7"));
+    assert_eq!(trail[3].actor.as_deref(), Some("ada"));
+    assert_eq!(trail[7].actor, None);
+}
+
+// Replaces the stored hash of `username` with `hash`
+async fn set_password_hash(env: &TestEnv, username: &str, hash: String) {
+    let mut record = env.users.find_by_username(username).await.unwrap().unwrap();
+    record.set_password_hash(hash);
+    env.users.update(username, &record).await.unwrap();
+}
+
+#[actix_web::test]
+async fn outdated_hashes_are_replaced_on_sign_in() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    assert!(env.users.find_by_username("jane").await.unwrap().unwrap().password_hash.starts_with("$argon2id$"));
+
+    // Stored before the switch to Argon2id
+    set_password_hash(&env, "jane", bcrypt::hash(PASSWORD, 4).unwrap()).await;
+    sign_in_cookie(&app, "jane").await;
+    let rehashed = env.users.find_by_username("jane").await.unwrap().unwrap().password_hash;
+    assert!(rehashed.starts_with("$argon2id$"));
+
+    // The same algorithm with a lower cost is outdated as well
+    let weaker = HashAlgorithm::Argon2id { memory_kib: 32, iterations: 1, parallelism: 1 };
+    let hash = Passwords::new(weaker).unwrap().hash(PASSWORD).await.unwrap();
+    set_password_hash(&env, "jane", hash.clone()).await;
+    sign_in_cookie(&app, "jane").await;
+    let rehashed = env.users.find_by_username("jane").await.unwrap().unwrap().password_hash;
+    assert_ne!(rehashed, hash);
+    assert!(rehashed.contains("m=64"));
+
+    // A current hash stays as it is
+    sign_in_cookie(&app, "jane").await;
+    assert_eq!(env.users.find_by_username("jane").await.unwrap().unwrap().password_hash, rehashed);
+}
+
+#[actix_web::test]
+async fn a_malformed_hash_is_an_error_not_a_panic() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    set_password_hash(&env, "jane", "not a hash".to_owned()).await;
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_web::test]
+async fn probes_report_liveness_and_readiness() {
+    let (app, _) = init_app().await;
+    for uri in ["/healthz", "/readyz"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK, "{uri}");
+    }
+}
+
+// A database that can't be reached, as when MongoDB is down
+struct Unreachable;
+
+fn unreachable<T>() -> RepositoryResult<T> {
+    let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+    Err(RepositoryError::from(mongodb::error::Error::from(refused)))
+}
+
+#[async_trait::async_trait]
+impl HealthCheck for Unreachable {
+    async fn check(&self) -> RepositoryResult<()> {
+        unreachable()
+    }
+}
+
+#[async_trait::async_trait]
+impl UserRepository for Unreachable {
+    async fn create(&self, _: &UserRecord) -> RepositoryResult<()> {
+        unreachable()
+    }
+
+    async fn find_by_id(&self, _: mongodb::bson::oid::ObjectId) -> RepositoryResult<Option<UserRecord>> {
+        unreachable()
+    }
+
+    async fn find_by_username(&self, _: &str) -> RepositoryResult<Option<UserRecord>> {
+        unreachable()
+    }
+
+    async fn find_by_email(&self, _: &str) -> RepositoryResult<Option<UserRecord>> {
+        unreachable()
+    }
+
+    async fn update(&self, _: &str, _: &UserRecord) -> RepositoryResult<bool> {
+        unreachable()
+    }
+
+    async fn soft_delete(&self, _: &str, _: DateTime) -> RepositoryResult<bool> {
+        unreachable()
+    }
+
+    async fn restore(&self, _: &str) -> RepositoryResult<Option<UserRecord>> {
+        unreachable()
+    }
+
+    async fn purge_deleted(&self, _: DateTime) -> RepositoryResult<Vec<UserRecord>> {
+        unreachable()
+    }
+
+    async fn find_page(&self, _: &repository::UserQuery) -> RepositoryResult<Vec<UserRecord>> {
+        unreachable()
+    }
+}
+
+#[actix_web::test]
+async fn an_unreachable_database_answers_503() {
+    let mut stores = Stores::in_memory();
+    stores.users = web::Data::from(Arc::new(Unreachable) as Arc<dyn UserRepository>);
+    stores.health = web::Data::from(Arc::new(Unreachable) as Arc<dyn HealthCheck>);
+    let (app, _) = init_app_with(stores).await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "5");
+    // The driver's message stays in the log
+    let body: Value = test::read_body_json(res).await;
+    assert!(!body.to_string().contains("refused"), "{body}");
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/get_user/jane").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn sign_in_is_rate_limited_per_client() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+    let sign_in_from = |peer: &str| {
+        test::TestRequest::post()
+            .uri("/sign_in")
+            .peer_addr(peer.parse().unwrap())
+            .set_json(json!({ "username": "jane", "password": PASSWORD }))
+            .to_request()
+    };
+
+    let limit = Config::default().rate_limit.sign_in_per_minute;
+    for _ in 0..limit {
+        let res = test::call_service(&app, sign_in_from("10.0.0.1:4000")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = test::call_service(&app, sign_in_from("10.0.0.1:4000")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+
+    let res = test::call_service(&app, sign_in_from("10.0.0.2:4000")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn the_frontend_can_call_across_origins() {
+    let (app, env) = init_app().await;
+    add(&app, &env, "jane").await;
+
+    let preflight = |origin: &str| {
+        test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/users/jane")
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization, content-type"))
+            .to_request()
+    };
+    // Answered before the sign in check of /users
+    let res = test::call_service(&app, preflight(SPA)).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), SPA);
+    let res = test::call_service(&app, preflight("https://evil.example.com")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .insert_header((header::ORIGIN, SPA))
+        .set_json(json!({ "username": "jane", "password": PASSWORD }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), SPA);
+    assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+    let exposed = res.headers().get(header::ACCESS_CONTROL_EXPOSE_HEADERS).unwrap().to_str().unwrap();
+    assert!(exposed.contains("ratelimit-remaining"));
+}
+
+#[actix_web::test]
+async fn users_stored_before_the_split_still_sign_in() {
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    // The shape the first version of the service wrote
+    let id = ObjectId::new();
+    let legacy = doc! {
+        "_id": id,
+        "first_name": "Jane",
+        "last_name": "Doe",
+        "username": "jane",
+        "email": "jane@example.com",
+        "password": bcrypt::hash(PASSWORD, 4).unwrap(),
+    };
+    let record: UserRecord = mongodb::bson::from_document(legacy).unwrap();
+    assert_eq!(record.created_at, id.timestamp());
+    assert_eq!(record.updated_at, record.created_at);
+    assert!(record.email_verified);
+    assert_eq!(record.role, Role::User);
+
+    let (app, env) = init_app().await;
+    env.users.create(&record).await.unwrap();
+    let req = test::TestRequest::post()
+        .uri("/sign_in")
+        .set_json(json!({ "username": "jane", "password": PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn duplicate_key_errors_name_the_field() {
+    use mongodb::bson::doc;
+    use mongodb::error::{BulkWriteFailure, ErrorKind, WriteError, WriteFailure};
+
+    let write_error = |error: mongodb::bson::Document| {
+        let error: WriteError = mongodb::bson::from_document(error).unwrap();
+        RepositoryError::from(mongodb::error::Error::from(ErrorKind::Write(WriteFailure::WriteError(error))))
+    };
+    // As an insert into a collection with the case-insensitive indexes fails
+    let err = write_error(doc! {
+        "code": 11000,
+        "codeName": "DuplicateKey",
+        "errmsg": "E11000 duplicate key error collection: myApp.users index: email_unique \
+                   collation: { locale: \"en\", strength: 2 } dup key: { email: \"jane@example.com\" }",
+    });
+    assert!(matches!(err, RepositoryError::Duplicate("email")));
+
+    let err = write_error(doc! {
+        "code": 11000,
+        "errmsg": "duplicate key",
+        "errInfo": { "keyPattern": { "username": 1 }, "keyValue": { "username": "jane" } },
+    });
+    assert!(matches!(err, RepositoryError::Duplicate("username")));
+
+    let err = write_error(doc! {
+        "code": 11000,
+        "errmsg": "E11000 duplicate key error collection: myApp.users index: _id_ dup key: { _id: 1 }",
+    });
+    assert!(matches!(err, RepositoryError::Duplicate("value")));
+
+    let bulk: BulkWriteFailure = mongodb::bson::from_document(doc! {
+        "writeErrors": [{
+            "index": 0,
+            "code": 11000,
+            "errmsg": "E11000 duplicate key error collection: myApp.users index: username_unique dup key: { username: \"jane\" }",
+        }],
+    })
+    .unwrap();
+    let err = RepositoryError::from(mongodb::error::Error::from(ErrorKind::BulkWrite(bulk)));
+    assert!(matches!(err, RepositoryError::Duplicate("username")));
+
+    let err = write_error(doc! { "code": 121, "errmsg": "Document failed validation" });
+    assert!(matches!(err, RepositoryError::Database(_)));
+}
+
+// Loads the settings from a file's contents and these environment variables
+fn load_config(file: Option<&str>, env: &[(&str, &str)], cli: Cli) -> Result<Config, ConfigError> {
+    let env = |name: &str| env.iter().find(|(var, _)| *var == name).map(|(_, value)| (*value).to_owned());
+    Config::from_sources(file.map(|contents| ("databases.toml", contents)), env, cli)
+}
+
+// The settings without defaults
+const REQUIRED_ENV: [(&str, &str); 2] = [
+    ("MONGODB_URI", "mongodb://localhost:27017"),
+    ("PUBLIC_URL", "https://app.example.com"),
+];
+
+#[actix_web::test]
+async fn settings_layer_file_then_env_then_cli() {
+    let file = r#"
+        [server]
+        bind = "0.0.0.0:1000"
+        workers = 2
+
+        [database]
+        name = "from_file"
+        users_collection = "people"
+    "#;
+    let mut env = REQUIRED_ENV.to_vec();
+    env.extend([("BIND_ADDRESS", "0.0.0.0:2000"), ("DB_NAME", "from_env")]);
+    let cli = Cli {
+        bind: Some("0.0.0.0:3000".to_owned()),
+        ..Cli::default()
+    };
+
+    let config = load_config(Some(file), &env, cli).unwrap();
+    assert_eq!(config.server.bind, "0.0.0.0:3000");
+    assert_eq!(config.database.name, "from_env");
+    assert_eq!(config.server.workers, Some(2));
+    assert_eq!(config.database.users_collection, "people");
+    // Untouched by every source
+    assert_eq!(config.database.timeout_secs, 5);
+}
+
+#[actix_web::test]
+async fn a_short_session_key_is_rejected() {
+    let mut env = REQUIRED_ENV.to_vec();
+    env.push(("SESSION_KEY", "too short"));
+    let err = load_config(None, &env, Cli::default()).err().expect("the key is too short");
+    assert!(err.to_string().contains("session.key must be at least 64 bytes"), "{err}");
+}
+
+#[actix_web::test]
+async fn credentials_cant_be_allowed_for_any_origin() {
+    let file = r#"
+        [cors]
+        allowed_origins = ["*"]
+        allow_credentials = true
+    "#;
+    let err = load_config(Some(file), &REQUIRED_ENV, Cli::default()).err().expect("the combination is rejected");
+    assert!(err.to_string().contains("cors.allow_credentials can't be used with any origin"), "{err}");
+}
+
+#[actix_web::test]
+async fn every_config_problem_is_reported_at_once() {
+    let file = r#"
+        [server]
+        workers = 0
+
+        [password]
+        algorithm = "bcrypt"
+        bcrypt_cost = 3
+    "#;
+    let env = [("DB_TIMEOUT_SECS", "soon")];
+    let err = load_config(Some(file), &env, Cli::default()).err().expect("the settings are invalid");
+    let err = err.to_string();
+    for problem in [
+        "DB_TIMEOUT_SECS: ",
+        "server.workers must be at least 1",
+        "database.uri is required",
+        "password.bcrypt_cost must be between 4 and 31",
+        "mail.public_url is required",
+    ] {
+        assert!(err.contains(problem), "{problem} missing from {err}");
+    }
+}
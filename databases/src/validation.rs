@@ -0,0 +1,192 @@
+//! Declarative validation of request bodies.
+//!
+//! A type implements `Validate` by listing rules per field; the `Validated`
+//! extractor runs them after deserializing and rejects the request with a
+//! 422 problem document naming every field that failed, not just the first.
+use std::ops::DerefMut;
+
+use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
+use errors::{AppError, FieldError};
+use futures_util::future::LocalBoxFuture;
+use regex::Regex;
+
+/// Every failed rule of a request body.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationErrors(pub Vec<FieldError>);
+
+impl std::fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields: Vec<_> = self.0.iter().map(|e| e.field.as_str()).collect();
+        write!(f, "validation failed for {}", fields.join(", "))
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Validation(errors.0)
+    }
+}
+
+/// Collects the results of the rules applied to a value.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    /// Starts the rules for one field.
+    pub fn field<'a>(&'a mut self, name: &'static str, value: &'a str) -> FieldRules<'a> {
+        FieldRules {
+            validator: self,
+            name,
+            value,
+        }
+    }
+
+    /// Adds an error that no single-field rule covers.
+    pub fn error(&mut self, field: &'static str, code: &'static str, message: impl Into<String>) {
+        self.errors.push(FieldError::new(field, message).with_code(code));
+    }
+
+    /// Fails with every error added so far.
+    pub fn finish(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(self.errors))
+        }
+    }
+}
+
+/// Rules for a single field. Each rule only reports if the field has no error yet.
+pub struct FieldRules<'a> {
+    validator: &'a mut Validator,
+    name: &'static str,
+    value: &'a str,
+}
+
+impl FieldRules<'_> {
+    fn has_error(&self) -> bool {
+        self.validator.errors.iter().any(|e| e.field == self.name)
+    }
+
+    fn check(self, ok: bool, code: &'static str, message: impl FnOnce() -> String) -> Self {
+        if !ok && !self.has_error() {
+            self.validator.error(self.name, code, message());
+        }
+        self
+    }
+
+    /// The value must have between `min` and `max` characters.
+    pub fn length(self, min: usize, max: usize) -> Self {
+        let len = self.value.chars().count();
+        self.check(len >= min, "too_short", || format!("must be at least {min} characters"))
+            .check(len <= max, "too_long", || format!("must be at most {max} characters"))
+    }
+
+    /// The value may only contain ASCII letters, digits, `_`, `.` and `-`.
+    pub fn username_charset(self) -> Self {
+        let ok = self
+            .value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        self.check(ok, "invalid_characters", || {
+            "may only contain letters, digits, '_', '.' and '-'".to_owned()
+        })
+    }
+
+    /// The value must look like an email address.
+    pub fn email(self) -> Self {
+        let ok = is_valid_email(self.value);
+        self.check(ok, "invalid_email", || "must be a valid email address".to_owned())
+    }
+
+    /// The value must satisfy the password policy: 8 to 128 characters, mixing
+    /// at least three of lowercase, uppercase, digits and other characters.
+    pub fn password_strength(self) -> Self {
+        let classes = [
+            self.value.chars().any(|c| c.is_lowercase()),
+            self.value.chars().any(|c| c.is_uppercase()),
+            self.value.chars().any(|c| c.is_ascii_digit()),
+            self.value.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        let mixed = classes.iter().filter(|&&present| present).count() >= 3;
+        self.length(8, 128).check(mixed, "too_weak", || {
+            "must mix at least three of lowercase, uppercase, digits and symbols".to_owned()
+        })
+    }
+
+    /// The value must equal `other`, e.g. a password and its confirmation.
+    pub fn matches(self, other: &str, message: &str) -> Self {
+        let ok = self.value == other;
+        self.check(ok, "mismatch", || message.to_owned())
+    }
+}
+
+/// A request body that can be checked field by field.
+pub trait Validate {
+    /// Cleans up the input before it is validated, e.g. trimming whitespace.
+    fn normalize(&mut self) {}
+
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Runs normalization and every rule of `value`.
+pub fn validate<T: Validate + ?Sized>(value: &mut T) -> Result<(), ValidationErrors> {
+    value.normalize();
+    let mut v = Validator::default();
+    value.validate(&mut v);
+    v.finish()
+}
+
+// Function to validate email format using a regular expression
+pub fn is_valid_email(email: &str) -> bool {
+    lazy_static::lazy_static! {
+        static ref EMAIL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$").unwrap();
+    }
+    EMAIL_REGEX.is_match(email)
+}
+
+/// Trims the address and lowercases it, so lookups don't depend on spelling.
+pub fn normalize_email(email: &mut String) {
+    *email = email.trim().to_lowercase();
+}
+
+/// Wraps another body extractor, such as `web::Form<T>` or `web::Json<T>`, and
+/// validates what it extracted.
+///
+/// Handlers taking `Validated<web::Form<T>>` only run for input that passed
+/// every rule of `T`; anything else gets a 422 problem document.
+pub struct Validated<E>(pub E);
+
+impl<E> Validated<E> {
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<E> std::ops::Deref for Validated<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E> FromRequest for Validated<E>
+where
+    E: FromRequest + DerefMut + 'static,
+    E::Target: Validate,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let fut = E::from_request(req, payload);
+        Box::pin(async move {
+            let mut inner = fut.await.map_err(Into::into)?;
+            validate(&mut *inner).map_err(AppError::from)?;
+            Ok(Validated(inner))
+        })
+    }
+}
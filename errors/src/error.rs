@@ -0,0 +1,233 @@
+//! The error type handlers return, rendered as RFC 7807 problem details.
+//!
+//! Every response carries a stable machine-readable `code` and a fresh
+//! `correlation_id`, which `ErrorReporting` logs too, so a report from a
+//! client can be matched with the server logs. Internal errors never show
+//! their details to the client.
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
+use std::fmt;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use derive_more::Display;
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+/// Response header repeating the correlation id of the problem.
+pub const CORRELATION_ID: &str = "x-correlation-id";
+
+pub type AppResult<T> = Result<T, AppError>;
+
+/// Why one field of a request was rejected.
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    /// Stable identifier of the broken rule, e.g. `too_short`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+}
+
+/// An internal error, with the backtrace of where it became an `AppError`.
+///
+/// The backtrace is only captured when `RUST_BACKTRACE` or
+/// `RUST_LIB_BACKTRACE` enable it.
+pub struct Report {
+    error: Box<dyn StdError + Send + Sync>,
+    backtrace: Backtrace,
+}
+
+impl Report {
+    pub fn new(error: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Report {
+            error: error.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// The messages of the error and of each of its sources, outermost first.
+    pub fn chain(&self) -> Vec<String> {
+        let error: &(dyn StdError + 'static) = self.error.as_ref();
+        std::iter::successors(Some(error), |&err| err.source())
+            .map(|err| err.to_string())
+            .collect()
+    }
+
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        (self.backtrace.status() == BacktraceStatus::Captured).then_some(&self.backtrace)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.chain().join(": "))
+    }
+}
+
+impl fmt::Debug for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum AppError {
+    #[display(fmt = "{}", _0)]
+    NotFound(String),
+    #[display(fmt = "{} invalid field(s)", "_0.len()")]
+    Validation(Vec<FieldError>),
+    #[display(fmt = "{}", _0)]
+    Unauthorized(String),
+    #[display(fmt = "{}", _0)]
+    Forbidden(String),
+    /// `field` names the value that clashes, if it is one of the request's.
+    #[display(fmt = "{}", detail)]
+    Conflict { detail: String, field: Option<String> },
+    #[display(fmt = "rate limited for {}s", retry_after_secs)]
+    RateLimited { retry_after_secs: u64 },
+    /// A dependency such as the database is down; worth retrying later.
+    #[display(fmt = "unavailable for {}s", retry_after_secs)]
+    Unavailable { retry_after_secs: u64 },
+    #[display(fmt = "internal error: {}", _0)]
+    Internal(Report),
+}
+
+impl StdError for AppError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            AppError::Internal(report) => Some(report.error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl AppError {
+    pub fn not_found(detail: impl fmt::Display) -> Self {
+        AppError::NotFound(detail.to_string())
+    }
+
+    pub fn unauthorized(detail: impl fmt::Display) -> Self {
+        AppError::Unauthorized(detail.to_string())
+    }
+
+    pub fn forbidden(detail: impl fmt::Display) -> Self {
+        AppError::Forbidden(detail.to_string())
+    }
+
+    pub fn conflict(detail: impl fmt::Display) -> Self {
+        AppError::Conflict {
+            detail: detail.to_string(),
+            field: None,
+        }
+    }
+
+    /// A conflict over the value of `field`, e.g. a username already taken.
+    pub fn conflict_on(field: impl Into<String>, detail: impl fmt::Display) -> Self {
+        AppError::Conflict {
+            detail: detail.to_string(),
+            field: Some(field.into()),
+        }
+    }
+
+    /// Wraps `err` and captures the backtrace of the call.
+    pub fn internal(err: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        AppError::Internal(Report::new(err))
+    }
+
+    /// The stable identifier clients can match on, unlike the wording of `detail`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Validation(_) => "validation_failed",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Conflict { .. } => "conflict",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Unavailable { .. } => "unavailable",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    // What the client is told; internal errors stay in the log
+    fn detail(&self) -> String {
+        match self {
+            AppError::Validation(_) => "The request has invalid fields".to_owned(),
+            AppError::RateLimited { retry_after_secs } => {
+                format!("Too many requests, retry in {retry_after_secs} seconds")
+            }
+            AppError::Unavailable { .. } => "The service is temporarily unavailable, try again later".to_owned(),
+            AppError::Internal(_) => "An unexpected error occurred".to_owned(),
+            AppError::NotFound(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::Conflict { detail, .. } => detail.clone(),
+        }
+    }
+
+    // The problem details document sent to the client
+    pub(crate) fn problem(&self, correlation_id: &str) -> Value {
+        let status = self.status_code();
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "detail": self.detail(),
+            "code": self.code(),
+            "correlation_id": correlation_id,
+        });
+        match self {
+            AppError::Validation(errors) => problem["errors"] = json!(errors),
+            AppError::Conflict { field: Some(field), .. } => problem["field"] = json!(field),
+            _ => {}
+        }
+        problem
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let correlation_id = Uuid::new_v4().to_string();
+        let problem = self.problem(&correlation_id);
+        let mut res = HttpResponse::build(self.status_code());
+        res.content_type(PROBLEM_JSON)
+            .insert_header((CORRELATION_ID, correlation_id));
+        if let AppError::RateLimited { retry_after_secs } | AppError::Unavailable { retry_after_secs } = self {
+            res.insert_header((header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        if let AppError::Unauthorized(_) = self {
+            res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        res.body(problem.to_string())
+    }
+}
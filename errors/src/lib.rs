@@ -0,0 +1,6 @@
+//! Errors shared by our services, and the middleware reporting them.
+pub mod error;
+pub mod reporting;
+
+pub use error::{AppError, AppResult, FieldError, Report};
+pub use reporting::ErrorReporting;
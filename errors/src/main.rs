@@ -1,21 +1,39 @@
-use actix_web::{error, get, middleware::Logger, App, HttpServer, Result};
+use actix_web::{get, middleware::Logger, web, App, HttpServer};
 use derive_more::{Display, Error};
-use log::info;
+
+use errors::{AppError, AppResult, ErrorReporting};
+use middleware::{Hooks, PropagateRequestId};
 
 #[derive(Debug, Display, Error)]
 #[display(fmt = "my error: {}", name)]
 pub struct MyError {
     name: &'static str,
+    source: std::io::Error,
 }
 
-// Use default implementation for `error_response()` method
-impl error::ResponseError for MyError {}
+// Failures of our own code are internal errors, hidden from the client
+impl From<MyError> for AppError {
+    fn from(err: MyError) -> Self {
+        AppError::internal(err)
+    }
+}
 
 #[get("/")]
-async fn index() -> Result<&'static str, MyError> {
-    let err = MyError { name: "test error" };
-    info!("{}", err);
-    Err(err)
+async fn index() -> AppResult<&'static str> {
+    let source = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "the database refused the connection");
+    Err(MyError { name: "test error", source }.into())
+}
+
+#[get("/items/{id}")]
+async fn item(id: web::Path<u32>) -> AppResult<String> {
+    match id.into_inner() {
+        1 => Ok("the first item".to_owned()),
+        id => Err(AppError::not_found(format!("No item with id {id}"))),
+    }
+}
+
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(item);
 }
 
 #[rustfmt::skip]
@@ -24,15 +42,26 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "info");
     std::env::set_var("RUST_BACKTRACE", "1");
     env_logger::init();
+    // Never in release builds, which could end up in production
+    let detailed = cfg!(debug_assertions)
+        && std::env::var("DETAILED_ERRORS").is_ok_and(|value| value == "1");
 
-    HttpServer::new(|| {
-        let logger = Logger::default();
+    HttpServer::new(move || {
+        // Like the default format, but without the query string, which can
+        // carry tokens, and with the id of the request
+        let logger = Logger::new(r#"%{x-request-id}o %a "%{METHOD}xi %U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+            .custom_request_replace("METHOD", |req| req.method().to_string());
 
         App::new()
+            .wrap(ErrorReporting::new().detailed(detailed))
+            .wrap(Hooks::new(PropagateRequestId))
             .wrap(logger)
-            .service(index)
+            .configure(configure)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,215 @@
+//! Logs the errors handlers return, with the request they failed.
+//!
+//! Server errors are logged at `error` level with their source chain and, if
+//! captured, the backtrace; client errors at `warn` level in one line. Only
+//! the path is logged, as query strings can carry tokens.
+//!
+//! The client keeps getting the sanitized problem details, unless the
+//! detailed error pages are turned on. Those show the chain and backtrace of
+//! internal errors, as HTML to browsers and as JSON otherwise, and are meant
+//! for local development only.
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, ContentType, HeaderMap};
+use actix_web::error::InternalError;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::{error, warn};
+use middleware::RequestId;
+use serde_json::json;
+
+use crate::error::{AppError, Report, CORRELATION_ID, PROBLEM_JSON};
+
+/// Middleware logging the errors of the services it wraps.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ErrorReporting {
+    detailed: bool,
+}
+
+impl ErrorReporting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows the chain and backtrace of internal errors to the client.
+    pub fn detailed(mut self, detailed: bool) -> Self {
+        self.detailed = detailed;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ErrorReporting
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ErrorReportingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ErrorReportingMiddleware {
+            service: Rc::new(service),
+            detailed: self.detailed,
+        }))
+    }
+}
+
+pub struct ErrorReportingMiddleware<S> {
+    service: Rc<S>,
+    detailed: bool,
+}
+
+// What the log line needs from the request, taken before it is handed on
+struct RequestContext {
+    method: Method,
+    path: String,
+    request_id: String,
+    wants_html: bool,
+}
+
+impl RequestContext {
+    fn new(req: &ServiceRequest) -> Self {
+        RequestContext {
+            method: req.method().clone(),
+            path: req.path().to_owned(),
+            // Assigned by `PropagateRequestId`, if it wraps this middleware
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .map_or_else(|| "-".to_owned(), ToString::to_string),
+            wants_html: req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("text/html")),
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for ErrorReportingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let context = RequestContext::new(&req);
+        let service = self.service.clone();
+        let detailed = self.detailed;
+
+        Box::pin(async move {
+            let res = match service.call(req).await {
+                Ok(res) => res,
+                // Failed in a middleware further in, leaving no request to answer with
+                Err(err) => {
+                    let res = err.error_response();
+                    let correlation_id = correlation_id(res.headers());
+                    log(&context, &err, res.status(), &correlation_id);
+                    let res = match (detailed, err.as_error::<AppError>()) {
+                        (true, Some(app_error @ AppError::Internal(report))) => {
+                            detailed_page(app_error, report, &correlation_id, context.wants_html)
+                        }
+                        _ => res,
+                    };
+                    // Carries the response logged, so the client gets the same correlation id
+                    return Err(InternalError::from_response(err, res).into());
+                }
+            };
+            let Some(err) = res.response().error() else {
+                return Ok(res.map_into_left_body());
+            };
+            let correlation_id = correlation_id(res.headers());
+            log(&context, err, res.status(), &correlation_id);
+
+            match (detailed, err.as_error::<AppError>()) {
+                (true, Some(app_error @ AppError::Internal(report))) => {
+                    let page = detailed_page(app_error, report, &correlation_id, context.wants_html);
+                    let (req, _) = res.into_parts();
+                    Ok(ServiceResponse::new(req, page).map_into_right_body())
+                }
+                _ => Ok(res.map_into_left_body()),
+            }
+        })
+    }
+}
+
+fn log(context: &RequestContext, err: &Error, status: StatusCode, correlation_id: &str) {
+    let RequestContext {
+        method,
+        path,
+        request_id,
+        ..
+    } = context;
+    if !status.is_server_error() {
+        warn!("{method} {path} request_id={request_id} correlation_id={correlation_id}: {err}");
+        return;
+    }
+    let report = match err.as_error::<AppError>() {
+        Some(AppError::Internal(report)) => Some(report),
+        _ => None,
+    };
+    let message = report.map_or_else(|| err.to_string(), Report::to_string);
+    match report.and_then(Report::backtrace) {
+        Some(backtrace) => error!(
+            "{method} {path} request_id={request_id} correlation_id={correlation_id}: {message}\n{backtrace}"
+        ),
+        None => error!("{method} {path} request_id={request_id} correlation_id={correlation_id}: {message}"),
+    }
+}
+
+fn correlation_id(headers: &HeaderMap) -> String {
+    headers
+        .get(CORRELATION_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_owned()
+}
+
+// The problem details plus what went wrong, for a developer's eyes only
+fn detailed_page(err: &AppError, report: &Report, correlation_id: &str, html: bool) -> HttpResponse {
+    let chain = report.chain();
+    let backtrace = report.backtrace().map(ToString::to_string);
+    let mut res = HttpResponse::InternalServerError();
+    res.insert_header((CORRELATION_ID, correlation_id));
+    if !html {
+        let mut problem = err.problem(correlation_id);
+        problem["chain"] = json!(chain);
+        problem["backtrace"] = json!(backtrace);
+        return res.content_type(PROBLEM_JSON).body(problem.to_string());
+    }
+
+    let causes: String = chain
+        .iter()
+        .map(|message| format!("<li>{}</li>", escape(message)))
+        .collect();
+    let backtrace = backtrace.map_or_else(
+        || "<p>No backtrace captured; set RUST_BACKTRACE=1 to get one.</p>".to_owned(),
+        |backtrace| format!("<pre>{}</pre>", escape(&backtrace)),
+    );
+    res.insert_header(ContentType::html()).body(format!(
+        "<!DOCTYPE html>\n<html><head><title>Internal Server Error</title></head><body>\
+         <h1>Internal Server Error</h1><p>Correlation id {}</p><ol>{causes}</ol>{backtrace}</body></html>",
+        escape(correlation_id)
+    ))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,181 @@
+//! HTTP level tests of the problem details responses.
+use actix_web::body::to_bytes;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, App, ResponseError};
+use serde_json::Value;
+
+use super::*;
+use errors::error::{CORRELATION_ID, PROBLEM_JSON};
+use errors::{ErrorReporting, FieldError};
+use middleware::{Hooks, PropagateRequestId};
+
+async fn problem(err: AppError) -> (StatusCode, header::HeaderMap, Value) {
+    let res = err.error_response();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = to_bytes(res.into_body()).await.unwrap();
+    (status, headers, serde_json::from_slice(&body).unwrap())
+}
+
+#[actix_web::test]
+async fn internal_errors_hide_their_details() {
+    let app = test::init_service(App::new().configure(configure)).await;
+    let req = test::TestRequest::get().uri("/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+    let correlation_id = res.headers().get(CORRELATION_ID).unwrap().to_str().unwrap().to_owned();
+
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["status"], 500);
+    assert_eq!(body["code"], "internal_error");
+    assert_eq!(body["correlation_id"], correlation_id);
+    assert!(!body.to_string().contains("test error"));
+}
+
+#[actix_web::test]
+async fn handlers_return_typed_errors() {
+    let app = test::init_service(App::new().configure(configure)).await;
+    let req = test::TestRequest::get().uri("/items/1").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get().uri("/items/2").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["title"], "Not Found");
+    assert_eq!(body["detail"], "No item with id 2");
+}
+
+#[actix_web::test]
+async fn every_variant_has_its_status_and_code() {
+    let cases = [
+        (AppError::Validation(vec![]), 422, "validation_failed"),
+        (AppError::unauthorized("Sign in first"), 401, "unauthorized"),
+        (AppError::forbidden("Admins only"), 403, "forbidden"),
+        (AppError::conflict("taken"), 409, "conflict"),
+        (AppError::RateLimited { retry_after_secs: 3 }, 429, "rate_limited"),
+        (AppError::Unavailable { retry_after_secs: 5 }, 503, "unavailable"),
+    ];
+    for (err, status, code) in cases {
+        let (actual, _, body) = problem(err).await;
+        assert_eq!(actual.as_u16(), status);
+        assert_eq!(body["status"], status);
+        assert_eq!(body["code"], code);
+    }
+}
+
+#[actix_web::test]
+async fn problems_carry_their_extra_details() {
+    let errors = vec![
+        FieldError::new("email", "is not an email"),
+        FieldError::new("password", "must be at least 8 characters").with_code("too_short"),
+    ];
+    let (_, headers, body) = problem(AppError::Validation(errors)).await;
+    assert_eq!(body["errors"][0]["field"], "email");
+    assert_eq!(body["errors"][0]["message"], "is not an email");
+    assert!(body["errors"][0].get("code").is_none());
+    assert_eq!(body["errors"][1]["code"], "too_short");
+    assert!(headers.get(header::RETRY_AFTER).is_none());
+
+    let (_, headers, _) = problem(AppError::RateLimited { retry_after_secs: 3 }).await;
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "3");
+    let (_, headers, _) = problem(AppError::Unavailable { retry_after_secs: 5 }).await;
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "5");
+
+    let (_, _, body) = problem(AppError::conflict_on("username", "The username is already taken")).await;
+    assert_eq!(body["field"], "username");
+    assert_eq!(body["detail"], "The username is already taken");
+
+    // Each response gets its own correlation id
+    let (_, _, first) = problem(AppError::forbidden("Admins only")).await;
+    let (_, _, second) = problem(AppError::forbidden("Admins only")).await;
+    assert_ne!(first["correlation_id"], second["correlation_id"]);
+}
+
+#[actix_web::test]
+async fn reporting_keeps_the_response_sanitized() {
+    let app = test::init_service(App::new().wrap(ErrorReporting::new()).configure(configure)).await;
+    let req = test::TestRequest::get().uri("/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "internal_error");
+    assert!(body.get("chain").is_none());
+    assert!(!body.to_string().contains("refused"));
+}
+
+#[actix_web::test]
+async fn detailed_pages_show_the_error_chain() {
+    let reporting = ErrorReporting::new().detailed(true);
+    let app = test::init_service(App::new().wrap(reporting).configure(configure)).await;
+
+    let req = test::TestRequest::get().uri("/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+    let correlation_id = res.headers().get(CORRELATION_ID).unwrap().to_str().unwrap().to_owned();
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["correlation_id"], correlation_id);
+    assert_eq!(
+        body["chain"],
+        serde_json::json!(["my error: test error", "the database refused the connection"])
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header((header::ACCEPT, "text/html"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("<li>the database refused the connection</li>"));
+
+    // Client errors are left alone
+    let req = test::TestRequest::get().uri("/items/2").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body.get("chain").is_none());
+}
+
+#[actix_web::test]
+async fn errors_keep_the_request_id() {
+    let app = test::init_service(
+        App::new()
+            .wrap(ErrorReporting::new().detailed(true))
+            .wrap(Hooks::new(PropagateRequestId))
+            .configure(configure),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header(("x-request-id", "req-1"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.headers().get("x-request-id").unwrap(), "req-1");
+}
+
+#[actix_web::test]
+async fn errors_from_inner_middleware_are_reported() {
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(|_, _| async {
+                Err::<ServiceResponse, _>(AppError::internal(std::io::Error::other("the cache is down")).into())
+            })
+            .wrap(ErrorReporting::new().detailed(true))
+            .configure(configure),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/items/1").to_request();
+    let err = test::try_call_service(&app, req).await.expect_err("the middleware fails");
+
+    // The response carried up is the one reported, with its details
+    let res = err.error_response();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let correlation_id = res.headers().get(CORRELATION_ID).unwrap().to_str().unwrap().to_owned();
+    let body: Value = serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["correlation_id"], correlation_id);
+    assert_eq!(body["chain"], serde_json::json!(["the cache is down"]));
+}
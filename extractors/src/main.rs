@@ -2,8 +2,10 @@
 //! An extractor can be accessed as an argument to a handler function. 
 //! Actix Web supports up to 12 extractors per handler function. 
 //! Argument position does not matter
-use actix_web::{get, web, App, HttpServer, Result};
-use serde::{Serialize, Deserialize};
+use actix_web::{get, post, web, App, HttpServer, Result};
+use serde::Deserialize;
+
+mod rejection;
 
 //It is also possible to extract path information to a type that implements the Deserialize trait 
 //from serde by matching dynamic segment names with field names. 
@@ -44,11 +46,52 @@ async fn index(info: web::Path<Info>) -> Result<String> {
     ))
 }
 
+#[derive(Deserialize)]
+struct Search {
+    q: String,
+    page: Option<u32>,
+}
+
+#[get("/search")]
+async fn search(query: web::Query<Search>) -> String {
+    format!("Page {} of results for {}", query.page.unwrap_or(1), query.q)
+}
+
+#[post("/sign_up")]
+async fn sign_up(form: web::Form<Info1>) -> String {
+    format!("Signed up {}!", form.username)
+}
+
+#[derive(Deserialize)]
+struct Note {
+    text: String,
+}
+
+// Notes are short, so their bodies get a much smaller limit than the default
+async fn add_note(note: web::Json<Note>) -> String {
+    format!("Saved a note of {} bytes", note.text.len())
+}
+
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.configure(rejection::configure)
+        .service(index)
+        .service(index1)
+        .service(search)
+        .service(sign_up)
+        .service(
+            web::resource("/notes")
+                .app_data(rejection::json_config(1024))
+                .route(web::post().to(add_note)),
+        );
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().service(index)
-        .service(index1))
+    HttpServer::new(|| App::new().configure(configure))
         .bind(("127.0.0.1", 8080))?
         .run()
         .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
\ No newline at end of file
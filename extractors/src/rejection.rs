@@ -0,0 +1,126 @@
+//! Uniform JSON responses for requests the extractors reject.
+//!
+//! Without these configs a body, path or query string that doesn't
+//! deserialize is answered with a terse text/plain 400. With them the body
+//! says which part of the request failed, the field when serde names one, and
+//! why:
+//!
+//! ```json
+//! {"error": "Invalid JSON body", "location": "body", "field": "username",
+//!  "reason": "missing field `username`", "line": 1, "column": 2}
+//! ```
+use actix_web::error::{
+    Error, InternalError, JsonPayloadError, PathError, QueryPayloadError, UrlencodedError,
+};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+
+/// Largest JSON body accepted unless a route sets its own limit.
+pub const DEFAULT_JSON_LIMIT: usize = 64 * 1024;
+/// Largest URL encoded form accepted unless a route sets its own limit.
+pub const DEFAULT_FORM_LIMIT: usize = 16 * 1024;
+
+#[derive(Debug, Serialize)]
+struct Rejection {
+    error: &'static str,
+    location: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+impl Rejection {
+    fn new(error: &'static str, location: &'static str, reason: String) -> Self {
+        Rejection {
+            error,
+            location,
+            field: named_field(&reason),
+            reason,
+            line: None,
+            column: None,
+        }
+    }
+
+    // Keeps the status actix picked for `err`, e.g. 413 for a body over the limit
+    fn into_error<E: ResponseError + 'static>(self, err: E) -> Error {
+        let res = HttpResponse::build(err.status_code()).json(self);
+        InternalError::from_response(err, res).into()
+    }
+}
+
+// serde only names the field for missing, unknown and duplicate ones, as in
+// "missing field `username`"; other errors only say what was wrong
+fn named_field(reason: &str) -> Option<String> {
+    ["missing field", "unknown field", "duplicate field"]
+        .iter()
+        .any(|prefix| reason.starts_with(prefix))
+        .then(|| reason.split('`').nth(1))
+        .flatten()
+        .map(str::to_owned)
+}
+
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> Error {
+    let rejection = match &err {
+        JsonPayloadError::Deserialize(json) => {
+            // serde_json appends the position, which is reported separately
+            let position = format!(" at line {} column {}", json.line(), json.column());
+            let reason = json.to_string();
+            let reason = reason.strip_suffix(&position).unwrap_or(&reason).to_owned();
+            Rejection {
+                line: Some(json.line()),
+                column: Some(json.column()),
+                ..Rejection::new("Invalid JSON body", "body", reason)
+            }
+        }
+        _ => Rejection::new("Invalid JSON body", "body", err.to_string()),
+    };
+    rejection.into_error(err)
+}
+
+fn form_error(err: UrlencodedError, _req: &HttpRequest) -> Error {
+    let reason = match &err {
+        UrlencodedError::Parse(form) => form.to_string(),
+        _ => err.to_string(),
+    };
+    Rejection::new("Invalid form body", "body", reason).into_error(err)
+}
+
+fn path_error(err: PathError, _req: &HttpRequest) -> Error {
+    let reason = match &err {
+        PathError::Deserialize(path) => path.to_string(),
+        _ => err.to_string(),
+    };
+    Rejection::new("Invalid path parameters", "path", reason).into_error(err)
+}
+
+fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> Error {
+    let reason = match &err {
+        QueryPayloadError::Deserialize(query) => query.to_string(),
+        _ => err.to_string(),
+    };
+    Rejection::new("Invalid query string", "query", reason).into_error(err)
+}
+
+/// JSON extractor config with the JSON error handler and a body limit in bytes.
+///
+/// Registered on a resource or scope, it overrides the app wide limit there.
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default().limit(limit).error_handler(json_error)
+}
+
+/// Form extractor config with the JSON error handler and a body limit in bytes.
+pub fn form_config(limit: usize) -> web::FormConfig {
+    web::FormConfig::default().limit(limit).error_handler(form_error)
+}
+
+/// Registers the JSON error handlers for every extractor, with the default limits.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(json_config(DEFAULT_JSON_LIMIT))
+        .app_data(form_config(DEFAULT_FORM_LIMIT))
+        .app_data(web::PathConfig::default().error_handler(path_error))
+        .app_data(web::QueryConfig::default().error_handler(query_error));
+}
@@ -0,0 +1,73 @@
+//! HTTP level tests of the JSON rejections.
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, App};
+use serde_json::{json, Value};
+
+use super::*;
+
+// Sends `req` to the app and returns the status and the JSON body
+async fn rejected(req: test::TestRequest) -> (StatusCode, Value) {
+    let app = test::init_service(App::new().configure(configure)).await;
+    let res = test::call_service(&app, req.to_request()).await;
+    let status = res.status();
+    assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+    (status, test::read_body_json(res).await)
+}
+
+#[actix_web::test]
+async fn a_missing_json_field_is_named() {
+    let req = test::TestRequest::get().uri("/").set_json(json!({ "name": "jane" }));
+    let (status, body) = rejected(req).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["location"], "body");
+    assert_eq!(body["field"], "username");
+    assert_eq!(body["reason"], "missing field `username`");
+}
+
+#[actix_web::test]
+async fn json_syntax_errors_have_a_position() {
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header(header::ContentType::json())
+        .set_payload("{\n  \"username\": }");
+    let (status, body) = rejected(req).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["line"], 2);
+    assert_eq!(body["column"], 15);
+    assert!(body.get("field").is_none());
+    assert!(!body["reason"].as_str().unwrap().contains("line"));
+}
+
+#[actix_web::test]
+async fn routes_can_lower_the_body_limit() {
+    let note = json!({ "text": "x".repeat(2000) });
+    let req = test::TestRequest::post().uri("/notes").set_json(&note);
+    let (status, body) = rejected(req).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["location"], "body");
+
+    let app = test::init_service(App::new().configure(configure)).await;
+    let req = test::TestRequest::post()
+        .uri("/notes")
+        .set_json(json!({ "text": "short" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn path_query_and_form_errors_are_json() {
+    let (status, body) = rejected(test::TestRequest::get().uri("/users/abc/bob")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["location"], "path");
+
+    let (status, body) = rejected(test::TestRequest::get().uri("/search?page=2")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["location"], "query");
+    assert_eq!(body["field"], "q");
+
+    let req = test::TestRequest::post().uri("/sign_up").set_form([("name", "jane")]);
+    let (status, body) = rejected(req).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["location"], "body");
+    assert_eq!(body["field"], "username");
+}
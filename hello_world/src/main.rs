@@ -1,6 +1,8 @@
 use actix_web::{
-    get, post, web, App, HttpResponse, HttpRequest, Responder, HttpServer
+    get, post, web, App, HttpResponse, Responder, HttpServer
 };
+use actix_web::http::header::ContentType;
+use middleware::{Csp, CspNonce, Hooks, SecurityHeaders, Source};
 
 
 #[get("/")]
@@ -16,11 +18,34 @@ async fn echo(req_body: String) -> impl Responder {
 async fn manual_hello() -> impl Responder {
     HttpResponse::Ok().body("Hey there!")
 }
+
+/// A page whose inline script runs because it carries the request's nonce.
+#[get("/page")]
+async fn page(nonce: CspNonce) -> impl Responder {
+    HttpResponse::Ok().insert_header(ContentType::html()).body(format!(
+        "<!DOCTYPE html>\n<html><body><p id=\"greeting\"></p>\
+         <script nonce=\"{nonce}\">document.getElementById('greeting').textContent = 'Hello World';</script>\
+         </body></html>"
+    ))
+}
+
+// Scripts only run with the nonce, and nothing may embed the pages
+fn security_headers() -> Hooks<SecurityHeaders> {
+    let csp = Csp::new()
+        .default_src([Source::SelfOrigin])
+        .script_src([Source::Nonce, Source::StrictDynamic])
+        .object_src([Source::None])
+        .base_uri([Source::None])
+        .frame_ancestors([Source::None]);
+    Hooks::new(SecurityHeaders::new().content_security_policy(Some(csp)))
+}
 #[actix_web::main]
 async fn main() ->std::io::Result<()>{
     HttpServer::new(|| {
         App::new()
+            .wrap(security_headers())
             .service(hello)
+            .service(page)
             .service(echo)
             .route("/hey", web::get().to(manual_hello))
     })
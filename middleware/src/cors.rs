@@ -0,0 +1,276 @@
+//! Cross-origin resource sharing, for frontends served from another origin.
+//!
+//! Preflight requests are answered here, before routing, so no handler needs
+//! an `OPTIONS` route. Other requests from an allowed origin get the
+//! `Access-Control-Allow-*` headers on their response. Requests from any
+//! other origin are served as usual, without the headers, and the browser
+//! keeps the response from the page.
+//!
+//! Every response says `Vary: Origin` unless it is the same for all origins,
+//! so caches don't hand one origin's response to another.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{Error, HttpResponse};
+use regex::Regex;
+
+use crate::hooks::Hook;
+
+/// An origin, or a set of them, allowed to make requests.
+#[derive(Clone, Debug)]
+pub enum AllowedOrigin {
+    /// Exactly this origin, e.g. "https://app.example.com".
+    Exact(String),
+    /// Any subdomain, written "https://*.example.com"; the domain itself is
+    /// not included.
+    Subdomains { scheme: String, domain: String },
+    /// Origins matching the whole pattern.
+    Regex(Regex),
+}
+
+impl AllowedOrigin {
+    /// Reads "https://*.example.com" as `Subdomains` and anything else as `Exact`.
+    pub fn parse(origin: &str) -> Self {
+        match origin.split_once("://*.") {
+            Some((scheme, domain)) => AllowedOrigin::Subdomains {
+                scheme: scheme.to_owned(),
+                domain: domain.to_owned(),
+            },
+            None => AllowedOrigin::Exact(origin.to_owned()),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Subdomains { scheme, domain } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .and_then(|subdomain| subdomain.strip_suffix('.'))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+                }),
+            AllowedOrigin::Regex(pattern) => pattern
+                .find(origin)
+                .is_some_and(|found| found.start() == 0 && found.end() == origin.len()),
+        }
+    }
+}
+
+/// Hook applying a CORS policy; nothing is allowed until configured.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    // Lowercase; `None` allows whatever the preflight asks for
+    headers: Option<HashSet<String>>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: Vec::new(),
+            any_origin: false,
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Some(HashSet::from(["content-type".to_owned()])),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows an exact origin, or every subdomain with "https://*.example.com".
+    pub fn allow_origin(self, origin: &str) -> Self {
+        self.allow(AllowedOrigin::parse(origin))
+    }
+
+    /// Allows the origins matching all of `pattern`.
+    pub fn allow_origin_regex(self, pattern: Regex) -> Self {
+        // Anchored, or "a|ab" would stop at "a" and turn down "ab"
+        let anchored = Regex::new(&format!("^(?:{})$", pattern.as_str())).expect("an anchored regex is still valid");
+        self.allow(AllowedOrigin::Regex(anchored))
+    }
+
+    pub fn allow(mut self, origin: AllowedOrigin) -> Self {
+        self.origins.push(origin);
+        self
+    }
+
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    /// Replaces the default GET, HEAD and POST.
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Replaces the default Content-Type.
+    pub fn allow_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.headers = Some(headers.into_iter().map(|name| name.as_str().to_owned()).collect());
+        self
+    }
+
+    pub fn allow_any_header(mut self) -> Self {
+        self.headers = None;
+        self
+    }
+
+    /// Response headers, beyond the safelisted ones, scripts may read.
+    pub fn expose_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.expose_headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Lets requests carry cookies and `Authorization`. Origins are then
+    /// always echoed, as browsers reject `*` with credentials.
+    pub fn allow_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // The same response for every origin can be cached regardless of it
+    fn varies_by_origin(&self) -> bool {
+        !self.any_origin || self.credentials
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    fn allow_origin_value(&self, origin: &HeaderValue) -> HeaderValue {
+        if self.varies_by_origin() {
+            origin.clone()
+        } else {
+            HeaderValue::from_static("*")
+        }
+    }
+
+    // Only called once `is_preflight` has checked both headers are there
+    fn preflight(&self, headers: &HeaderMap) -> HttpResponse {
+        let origin = headers.get(header::ORIGIN).expect("a preflight has an Origin");
+        let method = headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .expect("a preflight has an Access-Control-Request-Method");
+        let method_allowed = Method::from_bytes(method.as_bytes()).is_ok_and(|method| self.methods.contains(&method));
+        let requested: Vec<String> = headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|name| name.trim().to_ascii_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let headers_allowed = match &self.headers {
+            Some(allowed) => requested.iter().all(|name| allowed.contains(name)),
+            None => true,
+        };
+
+        let mut res = HttpResponse::NoContent();
+        res.insert_header((
+            header::VARY,
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+        ));
+        let allowed = origin.to_str().is_ok_and(|origin| self.allows(origin));
+        if !(allowed && method_allowed && headers_allowed) {
+            return res.status(StatusCode::FORBIDDEN).finish();
+        }
+
+        res.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin_value(origin)));
+        let methods = self.methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+        res.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, methods));
+        let headers = match &self.headers {
+            Some(allowed) => {
+                let mut allowed: Vec<_> = allowed.iter().map(String::as_str).collect();
+                allowed.sort_unstable();
+                allowed.join(", ")
+            }
+            None => requested.join(", "),
+        };
+        if !headers.is_empty() {
+            res.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, headers));
+        }
+        if self.credentials {
+            res.insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+        }
+        if let Some(max_age) = self.max_age {
+            res.insert_header((header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs()));
+        }
+        res.finish()
+    }
+
+    fn decorate(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if self.varies_by_origin() {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let Some(origin) = origin.filter(|origin| origin.to_str().is_ok_and(|origin| self.allows(origin))) else {
+            return;
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin_value(origin));
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if !self.expose_headers.is_empty() {
+            let exposed = self.expose_headers.iter().map(HeaderName::as_str).collect::<Vec<_>>().join(", ");
+            let exposed = HeaderValue::from_str(&exposed).expect("header names are valid header values");
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+    }
+}
+
+// A plain OPTIONS request, without the CORS headers, is the handlers' business
+fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(header::ORIGIN)
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+impl Hook for Cors {
+    // The request's origin; `None` for preflights, whose answers are complete already
+    type State = Option<Option<HeaderValue>>;
+
+    async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        if !is_preflight(req.method(), req.headers()) {
+            return Ok(None);
+        }
+        Ok(Some(self.preflight(req.headers())))
+    }
+
+    fn state(&self, req: &ServiceRequest) -> Self::State {
+        (!is_preflight(req.method(), req.headers())).then(|| req.headers().get(header::ORIGIN).cloned())
+    }
+
+    fn after<B>(&self, origin: Self::State, res: &mut HttpResponse<B>) {
+        if let Some(origin) = origin {
+            self.decorate(origin.as_ref(), res.headers_mut());
+        }
+    }
+}
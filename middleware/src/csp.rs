@@ -0,0 +1,210 @@
+//! Content Security Policy, telling browsers where a page may load scripts,
+//! styles and other resources from.
+//!
+//! A policy with `Source::Nonce` gets a fresh random nonce for every request.
+//! Handlers read it with the `CspNonce` extractor and put it on the inline
+//! scripts they trust:
+//!
+//! ```ignore
+//! async fn page(nonce: CspNonce) -> HttpResponse {
+//!     HttpResponse::Ok()
+//!         .content_type(ContentType::html())
+//!         .body(format!("<script nonce=\"{nonce}\">start()</script>"))
+//! }
+//! ```
+use std::fmt::{self, Write as _};
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+
+/// Where a directive allows resources from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Nowhere; alone in its directive.
+    None,
+    /// The page's own origin.
+    SelfOrigin,
+    /// Elements carrying the request's `CspNonce`.
+    Nonce,
+    /// Scripts loaded by already trusted scripts.
+    StrictDynamic,
+    UnsafeInline,
+    UnsafeEval,
+    /// A scheme, e.g. "data:" or "https:".
+    Scheme(&'static str),
+    /// A host, optionally with scheme, port and path, e.g. "https://cdn.example.com".
+    Host(String),
+}
+
+impl Source {
+    fn write(&self, out: &mut String, nonce: Option<&CspNonce>) {
+        match self {
+            Source::None => out.push_str("'none'"),
+            Source::SelfOrigin => out.push_str("'self'"),
+            Source::Nonce => {
+                let nonce = nonce.expect("a nonce is generated for policies using one");
+                write!(out, "'nonce-{nonce}'").unwrap();
+            }
+            Source::StrictDynamic => out.push_str("'strict-dynamic'"),
+            Source::UnsafeInline => out.push_str("'unsafe-inline'"),
+            Source::UnsafeEval => out.push_str("'unsafe-eval'"),
+            Source::Scheme(scheme) => out.push_str(scheme),
+            Source::Host(host) => out.push_str(host),
+        }
+    }
+}
+
+/// A Content Security Policy, built a directive at a time.
+#[derive(Clone, Debug, Default)]
+pub struct Csp {
+    directives: Vec<(&'static str, Vec<Source>)>,
+    upgrade_insecure_requests: bool,
+    report_only: bool,
+}
+
+impl Csp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directive `name`, replacing an earlier setting; for the
+    /// directives without a method of their own.
+    pub fn directive(mut self, name: &'static str, sources: impl IntoIterator<Item = Source>) -> Self {
+        let sources = sources.into_iter().collect();
+        match self.directives.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => *existing = sources,
+            None => self.directives.push((name, sources)),
+        }
+        self
+    }
+
+    /// The fallback for the `*-src` directives not set.
+    pub fn default_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("default-src", sources)
+    }
+
+    pub fn script_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("script-src", sources)
+    }
+
+    pub fn style_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("style-src", sources)
+    }
+
+    pub fn img_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("img-src", sources)
+    }
+
+    pub fn font_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("font-src", sources)
+    }
+
+    /// Where scripts may send requests, e.g. with `fetch`.
+    pub fn connect_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("connect-src", sources)
+    }
+
+    pub fn object_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("object-src", sources)
+    }
+
+    pub fn frame_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("frame-src", sources)
+    }
+
+    /// Which pages may embed this one; the modern `X-Frame-Options`.
+    pub fn frame_ancestors(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("frame-ancestors", sources)
+    }
+
+    pub fn base_uri(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("base-uri", sources)
+    }
+
+    pub fn form_action(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("form-action", sources)
+    }
+
+    /// Has browsers load `http:` resources over `https:`.
+    pub fn upgrade_insecure_requests(mut self) -> Self {
+        self.upgrade_insecure_requests = true;
+        self
+    }
+
+    /// Reports violations without blocking anything, to try a policy out.
+    pub fn report_only(mut self) -> Self {
+        self.report_only = true;
+        self
+    }
+
+    pub(crate) fn is_report_only(&self) -> bool {
+        self.report_only
+    }
+
+    pub(crate) fn uses_nonce(&self) -> bool {
+        self.directives
+            .iter()
+            .any(|(_, sources)| sources.contains(&Source::Nonce))
+    }
+
+    /// The header value, with `nonce` for `Source::Nonce`.
+    pub(crate) fn render(&self, nonce: Option<&CspNonce>) -> String {
+        let mut out = String::new();
+        for (name, sources) in &self.directives {
+            if !out.is_empty() {
+                out.push_str("; ");
+            }
+            out.push_str(name);
+            for source in sources {
+                out.push(' ');
+                source.write(&mut out, nonce);
+            }
+        }
+        if self.upgrade_insecure_requests {
+            if !out.is_empty() {
+                out.push_str("; ");
+            }
+            out.push_str("upgrade-insecure-requests");
+        }
+        out
+    }
+}
+
+/// The nonce of the current request, for `<script nonce="...">` and
+/// `<style nonce="...">`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // 128 random bits, hex encoded so it needs no escaping in HTML
+    pub(crate) fn generate() -> Self {
+        let bytes: [u8; 16] = rand::random();
+        CspNonce(bytes.iter().map(|b| format!("{b:02x}")).collect())
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for CspNonce {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CspNonce>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError("no Content-Security-Policy with a nonce is installed")),
+        )
+    }
+}
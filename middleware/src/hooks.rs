@@ -0,0 +1,124 @@
+//! Middleware from a pair of hooks, without writing the `Transform` and
+//! `Service` boilerplate of `SayHi` by hand.
+//!
+//! A `Hook` runs code before the wrapped service, where it can await lookups
+//! and answer the request itself, and after it, where it can change the
+//! response. Stacked with `App::wrap`, the last one registered runs first:
+//!
+//! ```text
+//! .wrap(Hooks::new(a)).wrap(Hooks::new(b))
+//! b.before -> a.before -> handler -> a.after -> b.after
+//! ```
+//!
+//! `after` runs on error responses too, including those of inner middleware
+//! that fail rather than respond. Their request is gone by then, so `after`
+//! gets the response alone, plus the `State` its hook took from the request
+//! before handing it on.
+use std::future::{ready, Future, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+
+/// Code run around a service by `Hooks`.
+pub trait Hook: 'static {
+    /// What `after` needs to know about the request.
+    type State: 'static;
+
+    /// Runs before the wrapped service, and may await state such as a store
+    /// from the app data. Returning a response short-circuits: the
+    /// service is never called and the response is sent instead.
+    fn before(&self, _req: &mut ServiceRequest) -> impl Future<Output = Result<Option<HttpResponse>, Error>> {
+        async { Ok(None) }
+    }
+
+    /// Takes what `after` needs from the request, once `before` has run.
+    fn state(&self, req: &ServiceRequest) -> Self::State;
+
+    /// Runs on the response, whether the service or `before` produced it,
+    /// or either failed.
+    fn after<B>(&self, _state: Self::State, _res: &mut HttpResponse<B>) {}
+}
+
+/// Middleware factory running a `Hook` around every request.
+pub struct Hooks<H> {
+    hook: Rc<H>,
+}
+
+impl<H: Hook> Hooks<H> {
+    pub fn new(hook: H) -> Self {
+        Hooks { hook: Rc::new(hook) }
+    }
+}
+
+impl<S, B, H> Transform<S, ServiceRequest> for Hooks<H>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    H: Hook,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = HooksMiddleware<S, H>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HooksMiddleware {
+            service: Rc::new(service),
+            hook: self.hook.clone(),
+        }))
+    }
+}
+
+pub struct HooksMiddleware<S, H> {
+    // Shared with the future of each call, which outlives `&self`
+    service: Rc<S>,
+    hook: Rc<H>,
+}
+
+impl<S, B, H> Service<ServiceRequest> for HooksMiddleware<S, H>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    H: Hook,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let hook = self.hook.clone();
+
+        Box::pin(async move {
+            let before = hook.before(&mut req).await;
+            let state = hook.state(&req);
+            let res = match before {
+                Ok(Some(early)) => Ok(req.into_response(early).map_into_right_body()),
+                Ok(None) => service.call(req).await.map(ServiceResponse::map_into_left_body),
+                Err(err) => Ok(req.error_response(err).map_into_right_body()),
+            };
+            match res {
+                Ok(mut res) => {
+                    hook.after(state, res.response_mut());
+                    Ok(res)
+                }
+                // Without the request there is no `ServiceResponse` to return,
+                // so the response goes out with the error for the server to send
+                Err(err) => {
+                    let mut res = err.error_response();
+                    hook.after(state, &mut res);
+                    Err(InternalError::from_response(err, res).into())
+                }
+            }
+        })
+    }
+}
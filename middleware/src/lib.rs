@@ -0,0 +1,22 @@
+//! Middleware shared by our services.
+//!
+//! `SayHi` shows what a middleware is made of. New ones are usually a `Hook`
+//! wrapped in `Hooks`.
+pub mod cors;
+pub mod csp;
+pub mod hooks;
+pub mod rate_limit;
+pub mod request_id;
+pub mod say_hi;
+pub mod security_headers;
+
+pub use cors::{AllowedOrigin, Cors};
+pub use csp::{Csp, CspNonce, Source};
+pub use hooks::{Hook, Hooks};
+pub use rate_limit::{ClientIp, InMemoryStore, Quota, RateLimit};
+pub use request_id::{PropagateRequestId, RequestId};
+pub use say_hi::SayHi;
+pub use security_headers::{FrameOptions, Hsts, ReferrerPolicy, SecurityHeaders};
+
+#[cfg(test)]
+mod tests;
@@ -1,83 +1,75 @@
-use std::future::{ready, Ready};
+use std::collections::HashSet;
 
-use actix_web::{
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
-};
-
-use actix_web::{dev::Service as _, web, App};
+use actix_web::dev::{Service as _, ServiceRequest};
+use actix_web::{web, App, Error, HttpResponse, HttpServer};
 use futures_util::future::FutureExt;
-use futures_util::future::LocalBoxFuture;
+use log::info;
 
-// There are two steps in middleware processing.
-// 1. Middleware initialization, middleware factory gets called with
-//    next service in chain as parameter.
-// 2. Middleware's call method gets called with normal request.
-pub struct SayHi;
+use middleware::{Hook, Hooks, PropagateRequestId, RequestId, SayHi};
 
-// Middleware factory is `Transform` trait
-// `S` - type of the next service
-// `B` - type of response's body
-impl<S, B> Transform<S, ServiceRequest> for SayHi
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = SayHiMiddleware<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+// Keys allowed into /admin; a real service would keep them in its database
+struct ApiKeys(HashSet<String>);
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(SayHiMiddleware { service }))
+impl ApiKeys {
+    async fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
     }
 }
 
-pub struct SayHiMiddleware<S> {
-    service: S,
-}
-
-impl<S, B> Service<ServiceRequest> for SayHiMiddleware<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
-
-    forward_ready!(service);
-
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!("Hi from start. You requested: {}", req.path());
+/// Answers 401 unless the request has a known `X-Api-Key`.
+struct RequireApiKey;
 
-        let fut = self.service.call(req);
+impl Hook for RequireApiKey {
+    type State = ();
 
-        Box::pin(async move {
-            let res = fut.await?;
-
-            println!("Hi from response");
-            Ok(res)
-        })
+    async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        let key = req.headers().get("x-api-key").and_then(|key| key.to_str().ok());
+        let keys = req.app_data::<web::Data<ApiKeys>>();
+        let known = match (key, keys) {
+            (Some(key), Some(keys)) => keys.contains(key).await,
+            _ => false,
+        };
+        Ok((!known).then(|| HttpResponse::Unauthorized().body("A valid X-Api-Key is required")))
     }
-}
 
+    fn state(&self, _req: &ServiceRequest) {}
+}
 
 #[actix_web::main]
-async fn main() {
-    let app = App::new()
-        .wrap_fn(|req, srv| {
-            println!("Hi from start. You requested: {}", req.path());
-            srv.call(req).map(|res| {
-                println!("Hi from response");
-                res
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let api_keys = std::env::var("ADMIN_API_KEYS").unwrap_or_default();
+    let api_keys = web::Data::new(ApiKeys(
+        api_keys.split(',').filter(|key| !key.is_empty()).map(str::to_owned).collect(),
+    ));
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(api_keys.clone())
+            .wrap(SayHi)
+            .wrap_fn(|req, srv| {
+                info!("Hi from start. You requested: {}", req.path());
+                srv.call(req).map(|res| {
+                    info!("Hi from response");
+                    res
+                })
             })
-        })
-        .route(
-            "/index.html",
-            web::get().to(|| async { "Hello, middleware!" }),
-        );
-}
\ No newline at end of file
+            .wrap(Hooks::new(PropagateRequestId))
+            .route(
+                "/index.html",
+                web::get().to(|| async { "Hello, middleware!" }),
+            )
+            .route(
+                "/request_id",
+                web::get().to(|id: RequestId| async move { format!("Your request is {id}") }),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(Hooks::new(RequireApiKey))
+                    .route("/stats", web::get().to(|| async { "All systems go" })),
+            )
+    })
+    .bind(("127.0.0.1", 8080))?
+    .run()
+    .await
+}
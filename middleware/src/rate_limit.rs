@@ -0,0 +1,364 @@
+//! Token bucket rate limiting.
+//!
+//! Every client gets a bucket per limited route, holding up to `burst`
+//! tokens and gaining one back every `interval`. Each request takes a token;
+//! with the bucket empty it is answered with 429 Too Many Requests. Clients
+//! are told where they stand by the `RateLimit-Limit`, `RateLimit-Remaining`
+//! and `RateLimit-Reset` headers, plus `Retry-After` once limited.
+//!
+//! Buckets live in a `RateLimitStore`. `InMemoryStore` suits a single
+//! instance; several instances behind a load balancer need a shared one. The
+//! store has to be created once and shared by every worker, or each worker
+//! counts on its own.
+//!
+//! ```ignore
+//! let store = Arc::new(InMemoryStore::new());
+//! HttpServer::new(move || {
+//!     let limit = RateLimit::new(ClientIp::peer(), store.clone())
+//!         .route("/sign_in", Quota::per_minute(10));
+//!     App::new().wrap(Hooks::new(limit))
+//! })
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use log::warn;
+use serde::Serialize;
+
+use crate::hooks::Hook;
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+const SHARDS: usize = 16;
+// A shard this big drops its full buckets, which are the same as no bucket
+const SWEEP_AT: usize = 4096;
+
+/// How many requests a client can make: `burst` at once, then one per `interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl Quota {
+    /// `count` requests per `period`, all of which may come at once.
+    ///
+    /// # Panics
+    /// If `count` is zero.
+    pub fn new(count: u32, period: Duration) -> Self {
+        assert!(count > 0, "a quota allows at least one request");
+        Quota {
+            burst: count,
+            interval: period / count,
+        }
+    }
+
+    pub fn per_second(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(60))
+    }
+
+    pub fn per_hour(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(60 * 60))
+    }
+
+    /// Allows `burst` requests at once, keeping the long-run rate.
+    pub fn with_burst(self, burst: u32) -> Self {
+        Quota {
+            burst: burst.max(1),
+            ..self
+        }
+    }
+}
+
+/// The outcome of taking a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset_after: Duration,
+    /// Until the next token, when not allowed.
+    pub retry_after: Duration,
+}
+
+/// Where the buckets are kept, e.g. in memory or in a shared cache.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Refills the bucket for `key` by the time passed, then takes a token.
+    fn take(&self, key: &str, quota: Quota) -> impl Future<Output = Result<Decision, Error>>;
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    quota: Quota,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let gained = now.duration_since(self.updated).as_secs_f64() / self.quota.interval.as_secs_f64();
+        self.tokens = (self.tokens + gained).min(f64::from(self.quota.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(now);
+        bucket.tokens >= f64::from(self.quota.burst)
+    }
+
+    fn take(&mut self, now: Instant) -> Decision {
+        self.refill(now);
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let interval = self.quota.interval;
+        Decision {
+            allowed,
+            limit: self.quota.burst,
+            remaining: self.tokens as u32,
+            reset_after: interval.mul_f64(f64::from(self.quota.burst) - self.tokens),
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                interval.mul_f64(1.0 - self.tokens)
+            },
+        }
+    }
+}
+
+/// Buckets in memory, split over shards so workers rarely wait for each other.
+pub struct InMemoryStore {
+    shards: Vec<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        InMemoryStore {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn take_at(&self, key: &str, quota: Quota, now: Instant) -> Decision {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS].lock().unwrap();
+        if !shard.contains_key(key) && shard.len() >= SWEEP_AT {
+            shard.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let bucket = shard.entry(key.to_owned()).or_insert(Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+            quota,
+        });
+        bucket.quota = quota;
+        bucket.take(now)
+    }
+}
+
+impl RateLimitStore for InMemoryStore {
+    async fn take(&self, key: &str, quota: Quota) -> Result<Decision, Error> {
+        Ok(self.take_at(key, quota, Instant::now()))
+    }
+}
+
+/// Picks the client a request counts against; `None` leaves it unlimited.
+///
+/// Any `Fn(&ServiceRequest) -> Option<String>` is one too.
+pub trait KeyExtractor: 'static {
+    fn key(&self, req: &ServiceRequest) -> Option<String>;
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&ServiceRequest) -> Option<String> + 'static,
+{
+    fn key(&self, req: &ServiceRequest) -> Option<String> {
+        self(req)
+    }
+}
+
+/// Keys requests by the IP address of the client.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp {
+    trust_proxy: bool,
+}
+
+impl ClientIp {
+    /// The address of the connection's peer.
+    pub fn peer() -> Self {
+        ClientIp { trust_proxy: false }
+    }
+
+    /// The address a proxy reports in `Forwarded` or `X-Forwarded-For`.
+    /// Only safe behind a proxy that sets them, as clients can send any.
+    pub fn forwarded() -> Self {
+        ClientIp { trust_proxy: true }
+    }
+}
+
+impl KeyExtractor for ClientIp {
+    fn key(&self, req: &ServiceRequest) -> Option<String> {
+        if !self.trust_proxy {
+            return req.peer_addr().map(|addr| format!("ip:{}", addr.ip()));
+        }
+        let info = req.connection_info();
+        let addr = info.realip_remote_addr()?;
+        // Forwarded addresses may come with a port
+        let ip = addr
+            .parse::<std::net::SocketAddr>()
+            .map_or_else(|_| addr.to_owned(), |addr| addr.ip().to_string());
+        Some(format!("ip:{ip}"))
+    }
+}
+
+/// Keys requests by the `T` an authentication middleware put in the request
+/// extensions, and anonymous ones by client IP.
+pub struct AuthenticatedUser<T> {
+    anonymous: ClientIp,
+    user: PhantomData<fn() -> T>,
+}
+
+impl<T> AuthenticatedUser<T> {
+    pub fn or(anonymous: ClientIp) -> Self {
+        AuthenticatedUser {
+            anonymous,
+            user: PhantomData,
+        }
+    }
+}
+
+impl<T: std::fmt::Display + 'static> KeyExtractor for AuthenticatedUser<T> {
+    fn key(&self, req: &ServiceRequest) -> Option<String> {
+        let user = req.extensions().get::<T>().map(|user| format!("user:{user}"));
+        user.or_else(|| self.anonymous.key(req))
+    }
+}
+
+/// Hook limiting requests per client with a quota per route, and optionally
+/// a default one for the routes without.
+pub struct RateLimit<K, S> {
+    key: K,
+    store: Arc<S>,
+    routes: HashMap<String, Quota>,
+    default: Option<Quota>,
+}
+
+impl<K: KeyExtractor, S: RateLimitStore> RateLimit<K, S> {
+    pub fn new(key: K, store: Arc<S>) -> Self {
+        RateLimit {
+            key,
+            store,
+            routes: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Limits the route registered as `pattern`, e.g. "/users/{username}".
+    pub fn route(mut self, pattern: &str, quota: Quota) -> Self {
+        self.routes.insert(pattern.to_owned(), quota);
+        self
+    }
+
+    /// Limits every route without a quota of its own.
+    pub fn default_quota(mut self, quota: Quota) -> Self {
+        self.default = Some(quota);
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct Limited {
+    error: String,
+}
+
+fn header_value(value: impl ToString) -> HeaderValue {
+    HeaderValue::from_str(&value.to_string()).expect("numbers are valid header values")
+}
+
+// Whole seconds, rounded up so clients don't come back too early
+fn secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+impl<K: KeyExtractor, S: RateLimitStore> Hook for RateLimit<K, S> {
+    type State = Option<Decision>;
+
+    async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        // Unmatched requests are limited by path, so probing for routes counts too
+        let pattern = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+        let Some(quota) = self.routes.get(&pattern).copied().or(self.default) else {
+            return Ok(None);
+        };
+        let Some(key) = self.key.key(req) else {
+            return Ok(None);
+        };
+        let decision = match self.store.take(&format!("{pattern} {key}"), quota).await {
+            Ok(decision) => decision,
+            // Better to serve everyone than no one while the store is down
+            Err(err) => {
+                warn!("rate limit store failed, letting the request through: {err}");
+                return Ok(None);
+            }
+        };
+
+        // With several limits on a request, the tightest one is reported
+        let tighter = req
+            .extensions()
+            .get::<Decision>()
+            .is_none_or(|reported| decision.remaining < reported.remaining);
+        if tighter {
+            req.extensions_mut().insert(decision);
+        }
+        if decision.allowed {
+            return Ok(None);
+        }
+        let retry_after = secs(decision.retry_after);
+        Ok(Some(
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after))
+                .json(Limited {
+                    error: format!("Too many requests, retry in {retry_after} seconds"),
+                }),
+        ))
+    }
+
+    fn state(&self, req: &ServiceRequest) -> Option<Decision> {
+        req.extensions().get::<Decision>().copied()
+    }
+
+    fn after<B>(&self, decision: Option<Decision>, res: &mut HttpResponse<B>) {
+        let headers = res.headers_mut();
+        // The innermost limit saw the decisions of all the others
+        if headers.contains_key(RATELIMIT_REMAINING) {
+            return;
+        }
+        if let Some(decision) = decision {
+            headers.insert(RATELIMIT_LIMIT, header_value(decision.limit));
+            headers.insert(RATELIMIT_REMAINING, header_value(decision.remaining));
+            headers.insert(RATELIMIT_RESET, header_value(secs(decision.reset_after)));
+        }
+    }
+}
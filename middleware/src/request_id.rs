@@ -0,0 +1,95 @@
+//! Gives every request an id to correlate its log lines by.
+//!
+//! The id comes from the `X-Request-Id` header if the client or a proxy sent
+//! a sensible one, and is a fresh UUIDv7 otherwise, so generated ids sort by
+//! time. Handlers get it with the `RequestId` extractor, and the response
+//! echoes it. The request header is overwritten with it too, so access logs
+//! can use `%{x-request-id}o` and anything reading the header sees the same id.
+use std::fmt;
+use std::future::{ready, Ready};
+
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use crate::hooks::Hook;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+// Longer ids are replaced, rather than copied into every log line
+const MAX_LEN: usize = 128;
+
+/// The id of the current request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // Ids from outside are kept only if they are safe to log as they are
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let id = value.to_str().ok()?;
+        let valid = !id.is_empty()
+            && id.len() <= MAX_LEN
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+        valid.then(|| RequestId(id.to_owned()))
+    }
+
+    fn generate() -> Self {
+        RequestId(Uuid::now_v7().to_string())
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<RequestId>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError("PropagateRequestId is not installed")),
+        )
+    }
+}
+
+/// Hook assigning the `RequestId`; wrap it in `Hooks` outside the middleware
+/// that log.
+pub struct PropagateRequestId;
+
+impl Hook for PropagateRequestId {
+    type State = RequestId;
+
+    async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        let id = req
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        let value = HeaderValue::from_str(id.as_str()).expect("request ids are valid header values");
+        req.headers_mut().insert(REQUEST_ID, value);
+        req.extensions_mut().insert(id);
+        Ok(None)
+    }
+
+    fn state(&self, req: &ServiceRequest) -> RequestId {
+        req.extensions().get::<RequestId>().cloned().expect("`before` assigns the id")
+    }
+
+    fn after<B>(&self, id: RequestId, res: &mut HttpResponse<B>) {
+        let value = HeaderValue::from_str(id.as_str()).expect("request ids are valid header values");
+        res.headers_mut().insert(REQUEST_ID, value);
+    }
+}
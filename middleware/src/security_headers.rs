@@ -0,0 +1,263 @@
+//! Response headers that have browsers turn on their protections.
+//!
+//! `SecurityHeaders::new()` sends `X-Content-Type-Options: nosniff`,
+//! `X-Frame-Options: DENY`, a `Referrer-Policy` and a `Permissions-Policy`,
+//! plus `Strict-Transport-Security` on responses sent over TLS. A
+//! `Content-Security-Policy` is opt-in, as every app needs its own.
+//!
+//! The innermost `SecurityHeaders` a request passes through decides, so a
+//! scope can wrap its own to loosen or tighten the app's:
+//!
+//! ```ignore
+//! App::new()
+//!     .wrap(Hooks::new(SecurityHeaders::new()))
+//!     .service(
+//!         web::scope("/widgets")
+//!             .wrap(Hooks::new(SecurityHeaders::new().frame_options(Some(FrameOptions::SameOrigin))))
+//!     )
+//! ```
+//!
+//! Headers a handler sets itself are left alone.
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpResponse};
+
+use crate::csp::{Csp, CspNonce};
+use crate::hooks::Hook;
+
+pub const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+
+/// How long browsers should only use HTTPS for the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hsts {
+    pub max_age: Duration,
+    pub include_subdomains: bool,
+    /// Asks to be on the browsers' built-in HTTPS only list.
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    fn default() -> Self {
+        Hsts::new(Duration::from_secs(365 * 24 * 60 * 60))
+    }
+}
+
+impl Hsts {
+    pub fn new(max_age: Duration) -> Self {
+        Hsts {
+            max_age,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+
+    pub fn include_subdomains(mut self) -> Self {
+        self.include_subdomains = true;
+        self
+    }
+
+    pub fn preload(mut self) -> Self {
+        self.preload = true;
+        self
+    }
+
+    fn value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// Whether other pages may show this one in a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOptions {
+    Deny,
+    SameOrigin,
+}
+
+/// How much of the page's URL goes in the `Referer` of the requests it makes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferrerPolicy {
+    NoReferrer,
+    NoReferrerWhenDowngrade,
+    Origin,
+    OriginWhenCrossOrigin,
+    SameOrigin,
+    StrictOrigin,
+    StrictOriginWhenCrossOrigin,
+    UnsafeUrl,
+}
+
+impl ReferrerPolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReferrerPolicy::NoReferrer => "no-referrer",
+            ReferrerPolicy::NoReferrerWhenDowngrade => "no-referrer-when-downgrade",
+            ReferrerPolicy::Origin => "origin",
+            ReferrerPolicy::OriginWhenCrossOrigin => "origin-when-cross-origin",
+            ReferrerPolicy::SameOrigin => "same-origin",
+            ReferrerPolicy::StrictOrigin => "strict-origin",
+            ReferrerPolicy::StrictOriginWhenCrossOrigin => "strict-origin-when-cross-origin",
+            ReferrerPolicy::UnsafeUrl => "unsafe-url",
+        }
+    }
+}
+
+// Shared by the `SecurityHeaders` a request passes through; set by the
+// innermost, so the outer ones keep out
+#[derive(Clone, Default)]
+struct Applied(Rc<Cell<bool>>);
+
+/// What `SecurityHeaders` needs of a request.
+pub struct RequestSecurity {
+    over_tls: bool,
+    nonce: Option<CspNonce>,
+    applied: Applied,
+}
+
+/// Hook adding the security headers; `None` leaves a header out.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    hsts: Option<Hsts>,
+    trust_proxy: bool,
+    frame_options: Option<FrameOptions>,
+    referrer_policy: Option<ReferrerPolicy>,
+    permissions_policy: Option<&'static str>,
+    csp: Option<Csp>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            hsts: Some(Hsts::default()),
+            trust_proxy: false,
+            frame_options: Some(FrameOptions::Deny),
+            referrer_policy: Some(ReferrerPolicy::StrictOriginWhenCrossOrigin),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=(), payment=()"),
+            csp: None,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hsts(mut self, hsts: Option<Hsts>) -> Self {
+        self.hsts = hsts;
+        self
+    }
+
+    /// Sends HSTS when a proxy reports TLS in `Forwarded` or
+    /// `X-Forwarded-Proto`. Only safe behind a proxy that sets them.
+    pub fn trust_proxy(mut self) -> Self {
+        self.trust_proxy = true;
+        self
+    }
+
+    pub fn frame_options(mut self, frame_options: Option<FrameOptions>) -> Self {
+        self.frame_options = frame_options;
+        self
+    }
+
+    pub fn referrer_policy(mut self, referrer_policy: Option<ReferrerPolicy>) -> Self {
+        self.referrer_policy = referrer_policy;
+        self
+    }
+
+    /// E.g. "camera=(), fullscreen=(self)".
+    pub fn permissions_policy(mut self, permissions_policy: Option<&'static str>) -> Self {
+        self.permissions_policy = permissions_policy;
+        self
+    }
+
+    pub fn content_security_policy(mut self, csp: Option<Csp>) -> Self {
+        self.csp = csp;
+        self
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, over_tls: bool, nonce: Option<&CspNonce>) {
+        let mut set = |name: HeaderName, value: HeaderValue| {
+            if !headers.contains_key(&name) {
+                headers.insert(name, value);
+            }
+        };
+        // Browsers ignore HSTS over plain HTTP, where anyone could have sent it
+        if let Some(hsts) = self.hsts.filter(|_| over_tls) {
+            set(header::STRICT_TRANSPORT_SECURITY, header_value(hsts.value()));
+        }
+        set(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        if let Some(frame_options) = self.frame_options {
+            let value = match frame_options {
+                FrameOptions::Deny => "DENY",
+                FrameOptions::SameOrigin => "SAMEORIGIN",
+            };
+            set(header::X_FRAME_OPTIONS, HeaderValue::from_static(value));
+        }
+        if let Some(referrer_policy) = self.referrer_policy {
+            set(header::REFERRER_POLICY, HeaderValue::from_static(referrer_policy.as_str()));
+        }
+        if let Some(permissions_policy) = self.permissions_policy {
+            set(PERMISSIONS_POLICY, HeaderValue::from_static(permissions_policy));
+        }
+        if let Some(csp) = &self.csp {
+            let name = if csp.is_report_only() {
+                header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+            } else {
+                header::CONTENT_SECURITY_POLICY
+            };
+            set(name, header_value(csp.render(nonce)));
+        }
+    }
+}
+
+fn header_value(value: String) -> HeaderValue {
+    HeaderValue::try_from(value).expect("security headers are valid header values")
+}
+
+impl Hook for SecurityHeaders {
+    type State = RequestSecurity;
+
+    async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        // One nonce per request, shared with the policies of inner scopes
+        let needs_nonce = self.csp.as_ref().is_some_and(Csp::uses_nonce);
+        if needs_nonce && !req.extensions().contains::<CspNonce>() {
+            req.extensions_mut().insert(CspNonce::generate());
+        }
+        if !req.extensions().contains::<Applied>() {
+            req.extensions_mut().insert(Applied::default());
+        }
+        Ok(None)
+    }
+
+    fn state(&self, req: &ServiceRequest) -> RequestSecurity {
+        let over_tls = if self.trust_proxy {
+            req.connection_info().scheme() == "https"
+        } else {
+            req.app_config().secure()
+        };
+        let extensions = req.extensions();
+        RequestSecurity {
+            over_tls,
+            nonce: extensions.get::<CspNonce>().cloned(),
+            applied: extensions.get::<Applied>().cloned().expect("`before` shares the flag"),
+        }
+    }
+
+    fn after<B>(&self, request: RequestSecurity, res: &mut HttpResponse<B>) {
+        if request.applied.0.replace(true) {
+            return;
+        }
+        self.apply(res.headers_mut(), request.over_tls, request.nonce.as_ref());
+    }
+}
@@ -0,0 +1,596 @@
+//! Tests of stacked middleware running around a handler.
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, Error, HttpResponse};
+
+use super::*;
+
+type Log = Arc<Mutex<Vec<String>>>;
+
+// Records when it runs, and answers 403 itself if told to
+struct Recorder {
+    name: &'static str,
+    log: Log,
+    forbid: bool,
+}
+
+impl Recorder {
+    fn new(name: &'static str, log: &Log) -> Self {
+        Recorder {
+            name,
+            log: log.clone(),
+            forbid: false,
+        }
+    }
+}
+
+impl Hook for Recorder {
+    type State = ();
+
+    async fn before(&self, _req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+        // Stands in for a lookup in a store
+        actix_web::rt::task::yield_now().await;
+        self.log.lock().unwrap().push(format!("{} before", self.name));
+        Ok(self.forbid.then(|| HttpResponse::Forbidden().finish()))
+    }
+
+    fn state(&self, _req: &ServiceRequest) {}
+
+    fn after<B>(&self, _: (), res: &mut HttpResponse<B>) {
+        self.log.lock().unwrap().push(format!("{} after", self.name));
+        res.headers_mut().append(
+            HeaderName::from_static("x-hooks"),
+            HeaderValue::from_static(self.name),
+        );
+    }
+}
+
+fn handler(log: &Log) -> impl Fn() -> std::future::Ready<&'static str> + Clone + 'static {
+    let log = log.clone();
+    move || {
+        log.lock().unwrap().push("handler".to_owned());
+        std::future::ready("done")
+    }
+}
+
+fn entries(log: &Log) -> Vec<String> {
+    log.lock().unwrap().clone()
+}
+
+#[actix_web::test]
+async fn the_last_registered_middleware_runs_first() {
+    let log = Log::default();
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(Recorder::new("inner", &log)))
+            .wrap(Hooks::new(Recorder::new("outer", &log)))
+            .route("/", web::get().to(handler(&log))),
+    )
+    .await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let hooks: Vec<_> = res.headers().get_all("x-hooks").collect();
+    assert_eq!(hooks, ["inner", "outer"]);
+    assert_eq!(
+        entries(&log),
+        ["outer before", "inner before", "handler", "inner after", "outer after"]
+    );
+}
+
+#[actix_web::test]
+async fn scope_middleware_runs_inside_app_middleware() {
+    let log = Log::default();
+    let app = test::init_service(
+        App::new().wrap(Hooks::new(Recorder::new("app", &log))).service(
+            web::scope("/admin")
+                .wrap(Hooks::new(Recorder::new("scope", &log)))
+                .route("", web::get().to(handler(&log))),
+        ),
+    )
+    .await;
+
+    test::call_service(&app, test::TestRequest::get().uri("/admin").to_request()).await;
+    assert_eq!(
+        entries(&log),
+        ["app before", "scope before", "handler", "scope after", "app after"]
+    );
+}
+
+#[actix_web::test]
+async fn a_short_circuit_skips_the_inner_middleware_and_handler() {
+    let log = Log::default();
+    let guard = Recorder {
+        forbid: true,
+        ..Recorder::new("guard", &log)
+    };
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(Recorder::new("inner", &log)))
+            .wrap(Hooks::new(guard))
+            .wrap(Hooks::new(Recorder::new("outer", &log)))
+            .route("/", web::get().to(handler(&log))),
+    )
+    .await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        entries(&log),
+        ["outer before", "guard before", "guard after", "outer after"]
+    );
+}
+
+#[actix_web::test]
+async fn errors_from_inner_middleware_still_reach_after() {
+    let log = Log::default();
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(|_req, _srv| async {
+                Err::<ServiceResponse, _>(actix_web::error::ErrorBadGateway("upstream down"))
+            })
+            .wrap(Hooks::new(Recorder::new("outer", &log)))
+            .wrap(Hooks::new(SecurityHeaders::new()))
+            .wrap(Hooks::new(PropagateRequestId))
+            .route("/", web::get().to(handler(&log))),
+    )
+    .await;
+
+    // The server sends the response the error carries
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header((request_id::REQUEST_ID, "lb-42:7"))
+        .to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    let res = err.error_response();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(res.headers().get("x-hooks").unwrap(), "outer");
+    assert_eq!(res.headers().get(request_id::REQUEST_ID).unwrap(), "lb-42:7");
+    assert_eq!(res.headers().get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(entries(&log), ["outer before", "outer after"]);
+}
+
+#[actix_web::test]
+async fn say_hi_passes_requests_through() {
+    let app = test::init_service(
+        App::new()
+            .wrap(SayHi)
+            .route("/", web::get().to(|| async { "hello" })),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/").to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, "hello");
+}
+
+#[actix_web::test]
+async fn request_ids_are_kept_or_generated_and_echoed() {
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(PropagateRequestId))
+            .route("/", web::get().to(|id: RequestId| async move { id.to_string() })),
+    )
+    .await;
+    let call = |id: Option<&str>| {
+        let mut req = test::TestRequest::get().uri("/");
+        if let Some(id) = id {
+            req = req.insert_header((request_id::REQUEST_ID, id));
+        }
+        let app = &app;
+        async move {
+            let res = test::call_service(app, req.to_request()).await;
+            let echoed = res.headers().get(request_id::REQUEST_ID).unwrap().to_str().unwrap().to_owned();
+            let seen = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert_eq!(echoed, seen);
+            seen
+        }
+    };
+
+    assert_eq!(call(Some("lb-42:7")).await, "lb-42:7");
+
+    let generated = call(None).await;
+    assert_eq!(uuid::Uuid::parse_str(&generated).unwrap().get_version_num(), 7);
+    assert_ne!(call(None).await, generated);
+
+    // Ids that would spoil the logs are replaced
+    for bad in ["has spaces", "{\"json\":1}", &"x".repeat(200)] {
+        let id = call(Some(bad)).await;
+        assert_ne!(id, bad);
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+    }
+}
+
+#[actix_web::test]
+async fn the_request_id_needs_its_middleware() {
+    let app = test::init_service(
+        App::new().route("/", web::get().to(|id: RequestId| async move { id.to_string() })),
+    )
+    .await;
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_web::test]
+async fn buckets_refill_one_token_per_interval() {
+    use std::time::{Duration, Instant};
+
+    let store = InMemoryStore::new();
+    let quota = Quota::per_minute(2);
+    let start = Instant::now();
+    let take = |secs: u64| store.take_at("client", quota, start + Duration::from_secs(secs));
+
+    let first = take(0);
+    assert!(first.allowed);
+    assert_eq!((first.limit, first.remaining), (2, 1));
+    assert!(take(0).allowed);
+    let limited = take(10);
+    assert!(!limited.allowed);
+    assert_eq!(limited.retry_after, Duration::from_secs(20));
+    assert_eq!(limited.reset_after, Duration::from_secs(50));
+
+    assert!(take(30).allowed);
+    assert!(!take(31).allowed);
+    // A long pause refills no more than the burst
+    assert_eq!(take(3600).remaining, 1);
+    assert_eq!(Quota::per_minute(2).with_burst(5).interval, Duration::from_secs(30));
+}
+
+fn from_peer(peer: &str, uri: &str) -> test::TestRequest {
+    test::TestRequest::get().uri(uri).peer_addr(peer.parse().unwrap())
+}
+
+#[actix_web::test]
+async fn clients_over_their_quota_get_429() {
+    let store = Arc::new(InMemoryStore::new());
+    let limit = RateLimit::new(ClientIp::peer(), store).route("/users/{name}", Quota::per_minute(2));
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(limit))
+            .route("/users/{name}", web::get().to(|| async { "user" }))
+            .route("/free", web::get().to(|| async { "free" })),
+    )
+    .await;
+
+    for (path, remaining) in [("/users/jane", "1"), ("/users/john", "0")] {
+        let res = test::call_service(&app, from_peer("10.0.0.1:1000", path).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), remaining);
+    }
+    let res = test::call_service(&app, from_peer("10.0.0.1:1001", "/users/jane").to_request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers().get("retry-after").unwrap(), "30");
+    assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+    assert_eq!(res.headers().get("ratelimit-reset").unwrap(), "60");
+
+    // Other clients and other routes are not affected
+    let res = test::call_service(&app, from_peer("10.0.0.2:1000", "/users/jane").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, from_peer("10.0.0.1:1000", "/free").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("ratelimit-limit").is_none());
+}
+
+#[actix_web::test]
+async fn signed_in_users_have_their_own_bucket() {
+    use actix_web::HttpMessage;
+    use rate_limit::AuthenticatedUser;
+
+    // What an authentication middleware would leave in the extensions
+    struct User(String);
+    impl std::fmt::Display for User {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+    struct SignIn;
+    impl Hook for SignIn {
+        type State = ();
+
+        async fn before(&self, req: &mut ServiceRequest) -> Result<Option<HttpResponse>, Error> {
+            if let Some(name) = req.headers().get("x-user").and_then(|name| name.to_str().ok()) {
+                let user = User(name.to_owned());
+                req.extensions_mut().insert(user);
+            }
+            Ok(None)
+        }
+
+        fn state(&self, _req: &ServiceRequest) {}
+    }
+
+    let store = Arc::new(InMemoryStore::new());
+    let limit = RateLimit::new(AuthenticatedUser::<User>::or(ClientIp::peer()), store)
+        .default_quota(Quota::per_hour(1));
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(limit))
+            .wrap(Hooks::new(SignIn))
+            .route("/", web::get().to(|| async { "hi" })),
+    )
+    .await;
+    let call = |user: Option<&'static str>| {
+        let mut req = test::TestRequest::get().uri("/").peer_addr("10.0.0.1:1000".parse().unwrap());
+        if let Some(user) = user {
+            req = req.insert_header(("x-user", user));
+        }
+        req.to_request()
+    };
+
+    // Same IP, different buckets
+    assert_eq!(test::call_service(&app, call(None)).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, call(Some("jane"))).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, call(Some("john"))).await.status(), StatusCode::OK);
+    let res = test::call_service(&app, call(Some("jane"))).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+fn preflight(origin: &str, method: &str) -> test::TestRequest {
+    test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri("/notes")
+        .insert_header(("origin", origin))
+        .insert_header(("access-control-request-method", method))
+}
+
+fn spa_cors() -> Cors {
+    use actix_web::http::{header, Method};
+
+    Cors::new()
+        .allow_origin("https://app.example.com")
+        .allow_origin("https://*.preview.example.com")
+        .allow_origin_regex(regex::Regex::new(r"http://localhost:\d+").unwrap())
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        .expose_headers([request_id::REQUEST_ID])
+        .allow_credentials()
+        .max_age(std::time::Duration::from_secs(600))
+}
+
+#[actix_web::test]
+async fn preflights_are_answered_before_the_handlers() {
+    let log = Log::default();
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(spa_cors()))
+            .route("/notes", web::delete().to(handler(&log))),
+    )
+    .await;
+
+    let req = preflight("https://app.example.com", "DELETE")
+        .insert_header(("access-control-request-headers", "Authorization, content-type"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let header = |name: &str| res.headers().get(name).unwrap().to_str().unwrap();
+    assert_eq!(header("access-control-allow-origin"), "https://app.example.com");
+    assert_eq!(header("access-control-allow-methods"), "GET, POST, DELETE");
+    assert_eq!(header("access-control-allow-headers"), "authorization, content-type");
+    assert_eq!(header("access-control-allow-credentials"), "true");
+    assert_eq!(header("access-control-max-age"), "600");
+    assert!(header("vary").starts_with("Origin"));
+
+    let refused = [
+        preflight("https://evil.example.net", "DELETE"),
+        preflight("https://app.example.com", "PUT"),
+        preflight("https://app.example.com", "POST").insert_header(("access-control-request-headers", "x-secret")),
+    ];
+    for req in refused {
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+        assert!(res.headers().contains_key("vary"));
+    }
+    assert!(entries(&log).is_empty());
+}
+
+#[actix_web::test]
+async fn simple_requests_from_allowed_origins_get_the_headers() {
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(spa_cors()))
+            .route("/notes", web::get().to(|| async { "notes" })),
+    )
+    .await;
+    let call = |origin: Option<&str>| {
+        let mut req = test::TestRequest::get().uri("/notes");
+        if let Some(origin) = origin {
+            req = req.insert_header(("origin", origin));
+        }
+        test::call_service(&app, req.to_request())
+    };
+
+    for origin in [
+        "https://app.example.com",
+        "https://pr-12.preview.example.com",
+        "http://localhost:5173",
+    ] {
+        let res = call(Some(origin)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("access-control-allow-origin").unwrap(), origin);
+        assert_eq!(res.headers().get("access-control-allow-credentials").unwrap(), "true");
+        assert_eq!(res.headers().get("access-control-expose-headers").unwrap(), "x-request-id");
+        assert_eq!(res.headers().get("vary").unwrap(), "Origin");
+    }
+
+    // Served all the same, but the browser keeps it from the page
+    for origin in [
+        Some("https://preview.example.com"),
+        Some("https://a.preview.example.com.evil.net"),
+        Some("http://localhost:5173.evil.net"),
+        None,
+    ] {
+        let res = call(origin).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+        assert_eq!(res.headers().get("vary").unwrap(), "Origin");
+    }
+}
+
+#[actix_web::test]
+async fn any_origin_is_a_wildcard_unless_credentials_are_allowed() {
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/public")
+                    .wrap(Hooks::new(Cors::new().allow_any_origin()))
+                    .route("", web::get().to(|| async { "public" })),
+            )
+            .service(
+                web::scope("/private")
+                    .wrap(Hooks::new(Cors::new().allow_any_origin().allow_credentials()))
+                    .route("", web::get().to(|| async { "private" })),
+            ),
+    )
+    .await;
+    let call = |uri: &str| {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("origin", "https://anywhere.example.org"));
+        test::call_service(&app, req.to_request())
+    };
+
+    let res = call("/public").await;
+    assert_eq!(res.headers().get("access-control-allow-origin").unwrap(), "*");
+    assert!(res.headers().get("vary").is_none());
+
+    let res = call("/private").await;
+    assert_eq!(
+        res.headers().get("access-control-allow-origin").unwrap(),
+        "https://anywhere.example.org"
+    );
+    assert_eq!(res.headers().get("vary").unwrap(), "Origin");
+}
+
+fn header<'a, B>(res: &'a ServiceResponse<B>, name: &str) -> Option<&'a str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[actix_web::test]
+async fn security_headers_are_added_and_hsts_only_over_tls() {
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/proxied")
+                    .wrap(Hooks::new(SecurityHeaders::new().trust_proxy()))
+                    .route("", web::get().to(|| async { "proxied" })),
+            )
+            .wrap(Hooks::new(SecurityHeaders::new()))
+            .route("/", web::get().to(|| async { "hi" }))
+            .route(
+                "/own",
+                web::get().to(|| async { HttpResponse::Ok().insert_header(("x-frame-options", "SAMEORIGIN")).finish() }),
+            ),
+    )
+    .await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(header(&res, "x-content-type-options"), Some("nosniff"));
+    assert_eq!(header(&res, "x-frame-options"), Some("DENY"));
+    assert_eq!(header(&res, "referrer-policy"), Some("strict-origin-when-cross-origin"));
+    assert!(header(&res, "permissions-policy").unwrap().contains("camera=()"));
+    assert!(header(&res, "content-security-policy").is_none());
+    assert!(header(&res, "strict-transport-security").is_none());
+
+    // Handlers have the last word
+    let res = test::call_service(&app, test::TestRequest::get().uri("/own").to_request()).await;
+    assert_eq!(header(&res, "x-frame-options"), Some("SAMEORIGIN"));
+
+    // Clients can't claim TLS unless a proxy is trusted to say so
+    let forwarded = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_request()
+    };
+    let res = test::call_service(&app, forwarded("/")).await;
+    assert!(header(&res, "strict-transport-security").is_none());
+    let res = test::call_service(&app, forwarded("/proxied")).await;
+    assert_eq!(header(&res, "strict-transport-security"), Some("max-age=31536000"));
+    let res = test::call_service(&app, test::TestRequest::get().uri("/proxied").to_request()).await;
+    assert!(header(&res, "strict-transport-security").is_none());
+}
+
+#[actix_web::test]
+async fn csp_nonces_are_fresh_for_every_request() {
+    let csp = Csp::new()
+        .default_src([Source::SelfOrigin])
+        .script_src([Source::Nonce, Source::StrictDynamic])
+        .img_src([Source::SelfOrigin, Source::Scheme("data:")])
+        .object_src([Source::None])
+        .upgrade_insecure_requests();
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(SecurityHeaders::new().content_security_policy(Some(csp))))
+            .route("/", web::get().to(|nonce: CspNonce| async move { nonce.to_string() })),
+    )
+    .await;
+    let call = || async {
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let policy = header(&res, "content-security-policy").unwrap().to_owned();
+        let nonce = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        (policy, nonce)
+    };
+
+    let (policy, nonce) = call().await;
+    assert_eq!(nonce.len(), 32);
+    assert_eq!(
+        policy,
+        format!(
+            "default-src 'self'; script-src 'nonce-{nonce}' 'strict-dynamic'; img-src 'self' data:; \
+             object-src 'none'; upgrade-insecure-requests"
+        )
+    );
+    assert_ne!(call().await.1, nonce);
+}
+
+#[actix_web::test]
+async fn a_scope_can_override_the_app_policy() {
+    let app_policy = Csp::new().default_src([Source::SelfOrigin]).script_src([Source::Nonce]);
+    let widgets = SecurityHeaders::new()
+        .frame_options(None)
+        .referrer_policy(Some(ReferrerPolicy::NoReferrer))
+        .content_security_policy(Some(
+            Csp::new()
+                .frame_ancestors([Source::Host("https://partner.example.com".to_owned())])
+                .report_only(),
+        ));
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(SecurityHeaders::new().content_security_policy(Some(app_policy))))
+            .service(
+                web::scope("/widgets")
+                    .wrap(Hooks::new(widgets))
+                    .route("", web::get().to(|| async { "widget" })),
+            )
+            .route("/", web::get().to(|| async { "home" })),
+    )
+    .await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/widgets").to_request()).await;
+    assert!(header(&res, "x-frame-options").is_none());
+    assert_eq!(header(&res, "referrer-policy"), Some("no-referrer"));
+    assert!(header(&res, "content-security-policy").is_none());
+    assert_eq!(
+        header(&res, "content-security-policy-report-only"),
+        Some("frame-ancestors https://partner.example.com")
+    );
+    assert_eq!(header(&res, "x-content-type-options"), Some("nosniff"));
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(header(&res, "x-frame-options"), Some("DENY"));
+    assert!(header(&res, "content-security-policy").unwrap().starts_with("default-src 'self'; script-src 'nonce-"));
+}
+
+#[actix_web::test]
+async fn the_nonce_needs_a_policy_using_one() {
+    let app = test::init_service(
+        App::new()
+            .wrap(Hooks::new(SecurityHeaders::new()))
+            .route("/", web::get().to(|nonce: CspNonce| async move { nonce.to_string() })),
+    )
+    .await;
+    let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
@@ -1,4 +1,5 @@
 use actix_web::{web, App, HttpServer, Result};
+use middleware::{Cors, Hooks};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -11,10 +12,22 @@ async fn index(info: web::Json<Info>) -> Result<String> {
     Ok(format!("Welcome {}!", info.username))
 }
 
+/// Lets the frontends in `CORS_ALLOWED_ORIGINS`, separated by commas, post
+/// JSON from another origin.
+fn cors() -> Hooks<Cors> {
+    let origins = std::env::var("CORS_ALLOWED_ORIGINS").unwrap_or_default();
+    let cors = origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .fold(Cors::new(), Cors::allow_origin);
+    Hooks::new(cors)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().route("/", web::post().to(index)))
+    HttpServer::new(|| App::new().wrap(cors()).route("/", web::post().to(index)))
         .bind(("127.0.0.1", 8080))?
         .run()
         .await
-}
\ No newline at end of file
+}
@@ -1,5 +1,6 @@
 use actix_files::NamedFile;
 use actix_web::{HttpRequest, Result};
+use middleware::{Csp, Hooks, SecurityHeaders, Source};
 use std::path::PathBuf;
 
 async fn index(req: HttpRequest) -> Result<NamedFile> {
@@ -11,7 +12,16 @@ async fn index(req: HttpRequest) -> Result<NamedFile> {
 async fn main() -> std::io::Result<()> {
     use actix_web::{web, App, HttpServer};
 
-    HttpServer::new(|| App::new().route("/{filename:.*}", web::get().to(index)))
+    HttpServer::new(|| {
+        // Served pages load what they need from here only
+        let csp = Csp::new()
+            .default_src([Source::SelfOrigin])
+            .object_src([Source::None])
+            .base_uri([Source::SelfOrigin]);
+        App::new()
+            .wrap(Hooks::new(SecurityHeaders::new().content_security_policy(Some(csp))))
+            .route("/{filename:.*}", web::get().to(index))
+    })
         .bind(("127.0.0.1", 8080))?
         .run()
         .await
@@ -1,4 +1,5 @@
 use actix_web::{get, App, HttpRequest, HttpServer, Responder, HttpResponse};
+use middleware::{Hooks, SecurityHeaders};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 #[get("/")]
@@ -17,7 +18,8 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
     builder.set_certificate_chain_file("cert.pem").unwrap();
 
-    HttpServer::new(|| App::new().service(index))
+    // Served over TLS, so HSTS is sent too
+    HttpServer::new(|| App::new().wrap(Hooks::new(SecurityHeaders::new())).service(index))
         .bind_openssl("127.0.0.1:8080", builder)?
         .run()
         .await